@@ -0,0 +1,328 @@
+//! `/client bubbles ...`: runtime control over what gets drawn and shared.
+
+use std::{cell::RefCell, os::raw::c_int, slice};
+
+use anyhow::{Context, Result, bail};
+use classicube_sys::{Chat_Add, OwnedChatCommand, OwnedString, cc_string};
+use tracing::debug;
+
+use crate::plugin::{
+    events::{
+        chat_message,
+        history::{self, Opened},
+        ignore_list,
+    },
+    networking::{message::PROTOCOL_VERSION, peers},
+    settings,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Help,
+    /// `None` toggles.
+    Enabled(Option<bool>),
+    TypingPreviews(Option<bool>),
+    Icons(Option<bool>),
+    Mute(String),
+    Unmute(String),
+    /// Open the history bubble over a player.
+    History(String),
+    /// Scroll the open history bubble by pages, positive being older.
+    HistoryScroll(isize),
+    HistoryClose,
+    Status,
+    /// List every persisted setting.
+    Settings,
+    Set(String, String),
+    Reset,
+}
+
+fn parse_switch(arg: Option<&str>) -> Result<Option<bool>> {
+    match arg.map(str::to_ascii_lowercase).as_deref() {
+        None => Ok(None),
+        Some("on" | "true" | "yes") => Ok(Some(true)),
+        Some("off" | "false" | "no") => Ok(Some(false)),
+        Some(other) => bail!("expected on or off, got {other:?}"),
+    }
+}
+
+impl Command {
+    fn parse(args: &[&str]) -> Result<Self> {
+        let Some((first, rest)) = args.split_first() else {
+            return Ok(Self::Help);
+        };
+        let arg = rest.first().copied();
+        Ok(match first.to_ascii_lowercase().as_str() {
+            "help" => Self::Help,
+            "on" => Self::Enabled(Some(true)),
+            "off" => Self::Enabled(Some(false)),
+            "toggle" => Self::Enabled(None),
+            "typing" => Self::TypingPreviews(parse_switch(arg)?),
+            "icons" => Self::Icons(parse_switch(arg)?),
+            "lifetime" => Self::Set(
+                "message-lifetime".to_string(),
+                arg.context("usage: lifetime <seconds>")?.to_string(),
+            ),
+            "mute" => Self::Mute(arg.context("usage: mute <player>")?.to_string()),
+            "unmute" => Self::Unmute(arg.context("usage: unmute <player>")?.to_string()),
+            "history" => match arg.context("usage: history <player>|up|down|close")? {
+                up if up.eq_ignore_ascii_case("up") => Self::HistoryScroll(1),
+                down if down.eq_ignore_ascii_case("down") => Self::HistoryScroll(-1),
+                close if close.eq_ignore_ascii_case("close") => Self::HistoryClose,
+                name => Self::History(name.to_string()),
+            },
+            "status" => Self::Status,
+            "settings" => Self::Settings,
+            "set" => match rest {
+                [name, value] => Self::Set(name.to_string(), value.to_string()),
+                _ => bail!("usage: set <setting> <value>, see /client bubbles settings"),
+            },
+            "reset" => Self::Reset,
+            other => bail!("unknown subcommand {other:?}, try /client bubbles help"),
+        })
+    }
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled { "&aon" } else { "&coff" }
+}
+
+fn execute(command: Command) -> Vec<String> {
+    match command {
+        Command::Help => vec![
+            "&a/client bubbles [on|off|toggle]".to_string(),
+            "&a/client bubbles typing|icons [on|off]".to_string(),
+            "&a/client bubbles lifetime <seconds>".to_string(),
+            "&a/client bubbles mute|unmute <player>".to_string(),
+            "&a/client bubbles history <player>|up|down|close".to_string(),
+            "&a/client bubbles status".to_string(),
+            "&a/client bubbles settings|reset|set <setting> <value>".to_string(),
+        ],
+
+        Command::Enabled(value) => {
+            let enabled = value.unwrap_or(!settings::get().enabled);
+            settings::update(|s| s.enabled = enabled);
+            vec![format!("&eBubbles {}", on_off(enabled))]
+        }
+
+        Command::TypingPreviews(value) => {
+            let enabled = value.unwrap_or(!settings::get().typing_previews);
+            settings::update(|s| s.typing_previews = enabled);
+            vec![format!("&eSharing typing previews {}", on_off(enabled))]
+        }
+
+        Command::Icons(value) => {
+            let enabled = value.unwrap_or(!settings::get().show_icons);
+            settings::update(|s| s.show_icons = enabled);
+            vec![format!("&ePresence icons {}", on_off(enabled))]
+        }
+
+        Command::Mute(name) => match ignore_list::add(&name) {
+            Ok(true) => vec![format!("&eMuted bubbles from &f{name}")],
+            Ok(false) => vec![format!("&f{name} &ewas already muted")],
+            Err(e) => vec![format!("&c{e:#}")],
+        },
+
+        Command::Unmute(name) => {
+            if ignore_list::remove(&name) {
+                vec![format!("&eUnmuted bubbles from &f{name}")]
+            } else {
+                vec![format!("&f{name} &ewasn't muted")]
+            }
+        }
+
+        Command::History(name) => match history::open(&name) {
+            Some(Opened::Bubble(count)) => vec![format!(
+                "&eShowing {count} lines from &f{name}&e, scroll with &a/client bubbles history up|down"
+            )],
+            Some(Opened::Page(page)) => {
+                let mut lines = vec![format!("&f{name} &eisn't here, their latest messages:")];
+                lines.extend(page);
+                lines
+            }
+            None => vec![format!("&eNo messages from &f{name} &eyet")],
+        },
+
+        Command::HistoryScroll(pages) => match history::scroll(pages) {
+            Some((name, first, last, total)) => {
+                vec![format!("&eLines {first}-{last} of {total} from &f{name}")]
+            }
+            None => vec!["&eNo history open, use &a/client bubbles history <player>".to_string()],
+        },
+
+        Command::HistoryClose => {
+            if history::close() {
+                vec!["&eClosed history".to_string()]
+            } else {
+                vec!["&eNo history open".to_string()]
+            }
+        }
+
+        Command::Status => {
+            let settings = settings::get();
+            let mut lines = vec![
+                format!(
+                    "&eBubbles {}&e, typing previews {}&e, icons {}&e, lifetime {:.1}s",
+                    on_off(settings.enabled),
+                    on_off(settings.typing_previews),
+                    on_off(settings.show_icons),
+                    settings.message_lifetime.as_secs_f32(),
+                ),
+                format!("&eProtocol version &f{PROTOCOL_VERSION}"),
+            ];
+
+            let peers = peers::get_all();
+            if peers.is_empty() {
+                lines.push("&eNo other players with the plugin seen yet".to_string());
+            } else {
+                let names = peers
+                    .iter()
+                    .map(|(id, info)| {
+                        let name =
+                            chat_message::get_player_name(*id).unwrap_or_else(|| format!("#{id}"));
+                        format!("&f{name} &7(v{})", info.version)
+                    })
+                    .collect::<Vec<_>>()
+                    .join("&e, ");
+                lines.push(format!("&ePeers: {names}"));
+            }
+
+            let muted = ignore_list::names();
+            if !muted.is_empty() {
+                lines.push(format!("&eMuted: &f{}", muted.join(", ")));
+            }
+            lines
+        }
+
+        Command::Settings => settings::describe()
+            .into_iter()
+            .map(|(name, value, help)| format!("&e{name} &f{value} &7({help})"))
+            .collect(),
+
+        Command::Set(name, value) => match settings::set_by_name(&name, &value) {
+            Ok(value) => vec![format!("&e{name} set to &f{value}")],
+            Err(e) => vec![format!("&c{e:#}")],
+        },
+
+        Command::Reset => {
+            settings::update(|s| *s = Default::default());
+            vec!["&eSettings reset to defaults".to_string()]
+        }
+    }
+}
+
+fn print(text: String) {
+    let text = OwnedString::new(text);
+    unsafe {
+        Chat_Add(&text.get_cc_string());
+    }
+}
+
+unsafe extern "C" fn c_command_callback(args: *const cc_string, args_count: c_int) {
+    let args = if args.is_null() || args_count <= 0 {
+        Vec::new()
+    } else {
+        unsafe { slice::from_raw_parts(args, args_count as usize) }
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+    };
+    debug!(?args, "command");
+
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    match Command::parse(&args) {
+        Ok(command) => execute(command).into_iter().for_each(print),
+        Err(e) => print(format!("&c{e:#}")),
+    }
+}
+
+thread_local!(
+    static COMMAND: RefCell<Option<OwnedChatCommand>> = const { RefCell::new(None) };
+);
+
+/// ClassiCube has no way to unregister a command, so there's no matching
+/// `free`: the registration (and the strings it points at) lives until the
+/// game exits.
+pub fn initialize() {
+    COMMAND.with_borrow_mut(|option| {
+        let mut command = OwnedChatCommand::new(
+            "Bubbles",
+            c_command_callback,
+            false,
+            vec![
+                "&a/client bubbles [on|off|toggle]",
+                "&eTurns chat bubbles on or off.",
+                "&eMore: typing, icons, lifetime, mute, history, status, settings",
+                "&eSee &a/client bubbles help &efor usage.",
+            ],
+        );
+        command.register();
+        *option = Some(command);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Command;
+
+    #[test]
+    fn parses_toggles() {
+        assert_eq!(Command::parse(&[]).unwrap(), Command::Help);
+        assert_eq!(
+            Command::parse(&["off"]).unwrap(),
+            Command::Enabled(Some(false))
+        );
+        assert_eq!(Command::parse(&["Toggle"]).unwrap(), Command::Enabled(None));
+        assert_eq!(
+            Command::parse(&["typing", "OFF"]).unwrap(),
+            Command::TypingPreviews(Some(false))
+        );
+        assert_eq!(Command::parse(&["icons"]).unwrap(), Command::Icons(None));
+        assert!(Command::parse(&["icons", "maybe"]).is_err());
+    }
+
+    #[test]
+    fn lifetime_is_a_setting() {
+        assert_eq!(
+            Command::parse(&["lifetime", "2.5"]).unwrap(),
+            Command::Set("message-lifetime".to_string(), "2.5".to_string())
+        );
+        assert!(Command::parse(&["lifetime"]).is_err());
+    }
+
+    #[test]
+    fn parses_mute() {
+        assert_eq!(
+            Command::parse(&["mute", "Goodly"]).unwrap(),
+            Command::Mute("Goodly".to_string())
+        );
+        assert!(Command::parse(&["unmute"]).is_err());
+        assert_eq!(
+            Command::parse(&["set", "font-size", "12"]).unwrap(),
+            Command::Set("font-size".to_string(), "12".to_string())
+        );
+        assert!(Command::parse(&["set", "font-size"]).is_err());
+        assert!(Command::parse(&["bogus"]).is_err());
+    }
+
+    #[test]
+    fn parses_history() {
+        assert_eq!(
+            Command::parse(&["history", "Goodly"]).unwrap(),
+            Command::History("Goodly".to_string())
+        );
+        assert_eq!(
+            Command::parse(&["history", "UP"]).unwrap(),
+            Command::HistoryScroll(1)
+        );
+        assert_eq!(
+            Command::parse(&["history", "down"]).unwrap(),
+            Command::HistoryScroll(-1)
+        );
+        assert_eq!(
+            Command::parse(&["history", "close"]).unwrap(),
+            Command::HistoryClose
+        );
+        assert!(Command::parse(&["history"]).is_err());
+    }
+}
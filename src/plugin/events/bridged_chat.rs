@@ -0,0 +1,86 @@
+//! Messages from people who aren't in the world, relayed by a server's
+//! IRC/Discord bridge. Kept here for the HUD bubble column to draw, since
+//! there's no entity to hang a bubble on.
+
+use std::{cell::RefCell, collections::VecDeque, time::Instant};
+
+/// Most messages shown at once; the oldest goes first.
+const MAX_MESSAGES: usize = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BridgedMessage {
+    /// Server lines, the first starting with the sender's name.
+    pub lines: Vec<String>,
+    pub received: Instant,
+}
+
+#[derive(Default)]
+struct Messages {
+    messages: VecDeque<BridgedMessage>,
+}
+
+impl Messages {
+    fn push(&mut self, name: &str, text: &str, now: Instant) {
+        if self.messages.len() >= MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(BridgedMessage {
+            lines: vec![format!("{name}&f: {text}")],
+            received: now,
+        });
+    }
+
+    fn extend_last(&mut self, line: &str) -> bool {
+        let Some(last) = self.messages.back_mut() else {
+            return false;
+        };
+        last.lines.push(line.to_string());
+        true
+    }
+}
+
+thread_local!(
+    static MESSAGES: RefCell<Messages> = Default::default();
+);
+
+pub fn push(name: &str, text: &str) {
+    MESSAGES.with_borrow_mut(|messages| messages.push(name, text, Instant::now()));
+}
+
+/// Adds a `> ...` continuation to the newest message. Returns false if
+/// there isn't one.
+pub fn extend_last(line: &str) -> bool {
+    MESSAGES.with_borrow_mut(|messages| messages.extend_last(line))
+}
+
+/// Oldest first.
+pub fn messages() -> Vec<BridgedMessage> {
+    MESSAGES.with_borrow(|messages| messages.messages.iter().cloned().collect())
+}
+
+pub fn free() {
+    MESSAGES.with_borrow_mut(|messages| messages.messages.clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::{MAX_MESSAGES, Messages};
+
+    #[test]
+    fn keeps_the_newest_and_continues_the_last() {
+        let mut messages = Messages::default();
+        let now = Instant::now();
+        assert!(!messages.extend_last("orphan"));
+        for i in 0..MAX_MESSAGES + 2 {
+            messages.push("ann", &i.to_string(), now);
+        }
+        assert!(messages.extend_last("more"));
+
+        let lines: Vec<_> = messages.messages.iter().map(|m| m.lines.clone()).collect();
+        assert_eq!(lines.len(), MAX_MESSAGES);
+        assert_eq!(lines[0], ["ann&f: 2"]);
+        assert_eq!(lines[MAX_MESSAGES - 1], ["ann&f: 6", "more"]);
+    }
+}
@@ -0,0 +1,91 @@
+//! Lines a server relays from IRC or Discord, like `(Discord) Name: text`.
+//! Nobody in the world said them, so instead of an entity's bubble they go
+//! to `bridged_chat` for the HUD column.
+//!
+//! The patterns use the chat-format syntax (`{name}`, `{text}`, `{any}`)
+//! and can be replaced with a `;`-separated `chatbubbles-bridge-patterns`
+//! option.
+
+use std::cell::RefCell;
+
+use tracing::{debug, warn};
+
+use super::format::{ChatFormat, Pattern};
+use crate::plugin::settings;
+
+const OPTION_NAME: &str = "bridge-patterns";
+
+const DEFAULT_PATTERNS: &str =
+    "(Discord) {name}: {text};[Discord] {name}: {text};(IRC) {name}: {text};[IRC] {name}: {text}";
+
+thread_local!(
+    static PATTERNS: RefCell<Vec<Pattern>> = Default::default();
+);
+
+/// Malformed patterns are logged and left out.
+fn parse_patterns(value: &str) -> Vec<Pattern> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .filter_map(|pattern| {
+            Pattern::parse(pattern)
+                .inspect_err(|e| warn!(?pattern, "ignoring bridge pattern: {:#}", e))
+                .ok()
+        })
+        .collect()
+}
+
+fn detect_with<'a>(patterns: &[Pattern], message: &'a str) -> Option<(&'a str, &'a str)> {
+    patterns.iter().find_map(|pattern| pattern.split(message))
+}
+
+/// The remote sender and their text, if `message` came over a bridge.
+pub fn detect(message: &str) -> Option<(String, String)> {
+    PATTERNS.with_borrow(|patterns| {
+        detect_with(patterns, message).map(|(name, text)| (name.to_string(), text.to_string()))
+    })
+}
+
+pub fn initialize() {
+    let value = settings::read_option(OPTION_NAME);
+    let patterns = parse_patterns(value.as_deref().unwrap_or(DEFAULT_PATTERNS));
+    debug!(patterns = patterns.len(), "loaded bridge patterns");
+    PATTERNS.with_borrow_mut(|slot| *slot = patterns);
+}
+
+pub fn free() {
+    PATTERNS.with_borrow_mut(Vec::clear);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DEFAULT_PATTERNS, detect_with, parse_patterns};
+
+    #[test]
+    fn default_patterns_catch_discord_and_irc() {
+        let patterns = parse_patterns(DEFAULT_PATTERNS);
+        assert_eq!(patterns.len(), 4);
+        assert_eq!(
+            detect_with(&patterns, "&9(Discord) &fsomeone: &fhello there"),
+            Some(("&fsomeone", "&fhello there"))
+        );
+        assert_eq!(
+            detect_with(&patterns, "[IRC] nick: hi: all"),
+            Some(("nick", "hi: all"))
+        );
+        assert_eq!(detect_with(&patterns, "&7Goodly: &f(Discord) x: y"), None);
+        assert_eq!(detect_with(&patterns, "(Discord) joined the server"), None);
+    }
+
+    #[test]
+    fn custom_patterns_replace_the_defaults() {
+        let patterns = parse_patterns("<{name}@discord> {text}; {broken} ;");
+        assert_eq!(patterns.len(), 1);
+        assert_eq!(
+            detect_with(&patterns, "<ann@discord> hey"),
+            Some(("ann", "hey"))
+        );
+        assert_eq!(detect_with(&patterns, "(Discord) ann: hey"), None);
+    }
+}
@@ -0,0 +1,453 @@
+//! How chat lines are laid out on the server we're connected to.
+//!
+//! Each `ChatFormat` knows one layout: where the speaker ends and what they
+//! said begins, plus whatever server announcements (whispers, AFK, ignore
+//! confirmations) it can recognize. MCGalaxy is the default; `generic`
+//! covers the `Name: text` / `<Name> text` / `[Rank] Name » text` family, and
+//! players can add their own patterns in a `chatbubbles-chat-formats` option:
+//!
+//! ```text
+//! chatbubbles-chat-formats=Freebuild|[{any}] {name} » {text};*|generic
+//! ```
+//!
+//! Rules are `server|format`, separated by `;`. `server` is matched, ignoring
+//! case, against the server name and MOTD (`*` matches any server); `format`
+//! is `mcgalaxy`, `generic` or a pattern. Every matching rule is tried in
+//! order, so a server can have several.
+
+use std::{cell::RefCell, rc::Rc};
+
+use anyhow::{Result, bail};
+use classicube_sys::Server;
+use tracing::{debug, warn};
+
+use super::{
+    WhisperKind, detect_action_line, detect_afk_line, detect_ignore_line,
+    detect_whisper_mode_transition, detect_whisper_prefix, is_continuation_message,
+};
+use crate::plugin::settings;
+
+const OPTION_NAME: &str = "chat-formats";
+
+/// Server software that doesn't speak MCGalaxy, recognized by name or MOTD
+/// when no rule matches.
+const KNOWN_SERVERS: &[(&str, &str)] = &[
+    ("fcraft", "generic"),
+    ("800craft", "generic"),
+    ("legendcraft", "generic"),
+    ("procraft", "generic"),
+];
+
+pub trait ChatFormat {
+    /// `mcgalaxy`, `generic`, or the pattern as written; for logs and tests.
+    fn describe(&self) -> String;
+
+    /// Splits a chat line into the speaker as shown (colors, titles and all)
+    /// and what they said. `None` if it isn't a chat line in this layout.
+    fn split<'a>(&self, message: &'a str) -> Option<(&'a str, &'a str)>;
+
+    /// The rest of the previous message, if `message` continues it.
+    fn continuation<'a>(&self, _message: &'a str) -> Option<&'a str> {
+        None
+    }
+
+    /// Actor as written and what they did, for `/me` lines.
+    fn action<'a>(&self, _message: &'a str) -> Option<(&'a str, &'a str)> {
+        None
+    }
+
+    /// Whisper marker and the chat line after it.
+    fn whisper<'a>(&self, _message: &'a str) -> Option<(WhisperKind, &'a str)> {
+        None
+    }
+
+    /// `Some(true)` entering auto-whisper mode, `Some(false)` leaving it.
+    fn whisper_mode_transition(&self, _message: &str) -> Option<bool> {
+        None
+    }
+
+    /// Nick and whether they went AFK.
+    fn afk_line(&self, _message: &str) -> Option<(String, bool)> {
+        None
+    }
+
+    /// Nick and whether we now ignore them.
+    fn ignore_line(&self, _message: &str) -> Option<(String, bool)> {
+        None
+    }
+}
+
+pub struct McGalaxy;
+
+impl ChatFormat for McGalaxy {
+    fn describe(&self) -> String {
+        "mcgalaxy".to_string()
+    }
+
+    fn split<'a>(&self, message: &'a str) -> Option<(&'a str, &'a str)> {
+        // The nick always carries at least a color code, so anything shorter
+        // is someone's text with a colon in it.
+        let pos = message.find(": ").filter(|&pos| pos >= 3)?;
+        Some((&message[..pos], &message[pos + 2..]))
+    }
+
+    fn continuation<'a>(&self, message: &'a str) -> Option<&'a str> {
+        is_continuation_message(message)
+    }
+
+    fn action<'a>(&self, message: &'a str) -> Option<(&'a str, &'a str)> {
+        detect_action_line(message)
+    }
+
+    fn whisper<'a>(&self, message: &'a str) -> Option<(WhisperKind, &'a str)> {
+        detect_whisper_prefix(message)
+    }
+
+    fn whisper_mode_transition(&self, message: &str) -> Option<bool> {
+        detect_whisper_mode_transition(message)
+    }
+
+    fn afk_line(&self, message: &str) -> Option<(String, bool)> {
+        detect_afk_line(message)
+    }
+
+    fn ignore_line(&self, message: &str) -> Option<(String, bool)> {
+        detect_ignore_line(message)
+    }
+}
+
+/// Byte length of the `&X` color codes `message` starts with, so parsers
+/// can skip whatever colors a server puts in front of a marker.
+pub(super) fn leading_color_codes(message: &str) -> usize {
+    let bytes = message.as_bytes();
+    let mut i = 0;
+    while i + 1 < bytes.len() && bytes[i] == b'&' && bytes[i + 1].is_ascii_alphanumeric() {
+        i += 2;
+    }
+    i
+}
+
+/// What `Generic` looks for between a name and their text.
+const SEPARATORS: &[&str] = &[": ", " \u{BB} "];
+
+/// `<Name> text`, or a name and text around the first `: ` or ` » `.
+pub struct Generic;
+
+impl ChatFormat for Generic {
+    fn describe(&self) -> String {
+        "generic".to_string()
+    }
+
+    fn split<'a>(&self, message: &'a str) -> Option<(&'a str, &'a str)> {
+        let start = leading_color_codes(message);
+        if let Some(rest) = message[start..].strip_prefix('<') {
+            if let Some(end) = rest.find("> ") {
+                return Some((&rest[..end], &rest[end + 2..]));
+            }
+        }
+        SEPARATORS
+            .iter()
+            .filter_map(|separator| {
+                let pos = message.find(separator).filter(|&pos| pos > start)?;
+                Some((pos, separator.len()))
+            })
+            .min_by_key(|(pos, _)| *pos)
+            .map(|(pos, len)| (&message[..pos], &message[pos + len..]))
+    }
+
+    fn action<'a>(&self, message: &'a str) -> Option<(&'a str, &'a str)> {
+        detect_action_line(message)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Literal(String),
+    /// `{name}`: the speaker.
+    Name,
+    /// `{text}`: what they said.
+    Text,
+    /// `{any}`: skipped, like a rank or title.
+    Any,
+}
+
+/// A user's layout, written as literal text with `{name}`, `{text}` and
+/// `{any}` holes. Color codes in the line are skipped when matching the
+/// literal parts, so patterns are written as the line reads on screen.
+pub struct Pattern {
+    source: String,
+    tokens: Vec<Token>,
+}
+
+impl Pattern {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut tokens = Vec::new();
+        let mut rest = source;
+        while !rest.is_empty() {
+            let Some(open) = rest.find('{') else {
+                tokens.push(Token::Literal(rest.to_string()));
+                break;
+            };
+            if open > 0 {
+                tokens.push(Token::Literal(rest[..open].to_string()));
+            }
+            let Some(close) = rest[open..].find('}') else {
+                bail!("unclosed {{ in {source:?}");
+            };
+            tokens.push(match &rest[open + 1..open + close] {
+                "name" => Token::Name,
+                "text" => Token::Text,
+                "any" => Token::Any,
+                other => bail!("unknown {{{other}}}, expected {{name}}, {{text}} or {{any}}"),
+            });
+            rest = &rest[open + close + 1..];
+        }
+        for (required, token) in [("{name}", Token::Name), ("{text}", Token::Text)] {
+            if tokens.iter().filter(|t| **t == token).count() != 1 {
+                bail!("{source:?} needs exactly one {required}");
+            }
+        }
+        Ok(Self {
+            source: source.to_string(),
+            tokens,
+        })
+    }
+}
+
+/// Where `literal` ends if it starts at `pos`, skipping any color codes in
+/// `message` before each of its characters.
+fn match_literal(literal: &str, message: &str, mut pos: usize) -> Option<usize> {
+    for c in literal.chars() {
+        if c != '&' {
+            pos += leading_color_codes(&message[pos..]);
+        }
+        if !message[pos..].starts_with(c) {
+            return None;
+        }
+        pos += c.len_utf8();
+    }
+    Some(pos)
+}
+
+/// Matches `tokens` against the rest of `message` from `pos`, filling in the
+/// captures. Holes take as little as they can, so `{name}` stops at the
+/// first separator that lets the rest match.
+fn match_tokens<'a>(
+    tokens: &[Token],
+    message: &'a str,
+    pos: usize,
+    captures: &mut (Option<&'a str>, Option<&'a str>),
+) -> bool {
+    let Some((token, rest)) = tokens.split_first() else {
+        return pos == message.len();
+    };
+    if let Token::Literal(literal) = token {
+        return match_literal(literal, message, pos)
+            .is_some_and(|end| match_tokens(rest, message, end, captures));
+    }
+    for end in (pos + 1..=message.len()).filter(|&end| message.is_char_boundary(end)) {
+        if match_tokens(rest, message, end, captures) {
+            match token {
+                Token::Name => captures.0 = Some(&message[pos..end]),
+                Token::Text => captures.1 = Some(&message[pos..end]),
+                Token::Any | Token::Literal(_) => {}
+            }
+            return true;
+        }
+    }
+    false
+}
+
+impl ChatFormat for Pattern {
+    fn describe(&self) -> String {
+        self.source.clone()
+    }
+
+    fn split<'a>(&self, message: &'a str) -> Option<(&'a str, &'a str)> {
+        let mut captures = (None, None);
+        if !match_tokens(&self.tokens, message, 0, &mut captures) {
+            return None;
+        }
+        let (name, text) = captures;
+        Some((name?.trim(), text?))
+    }
+}
+
+/// `mcgalaxy`, `generic`, or else a pattern.
+fn parse_format(value: &str) -> Result<Rc<dyn ChatFormat>> {
+    Ok(match value.to_ascii_lowercase().as_str() {
+        "mcgalaxy" => Rc::new(McGalaxy),
+        "generic" => Rc::new(Generic),
+        _ => Rc::new(Pattern::parse(value)?),
+    })
+}
+
+struct Rule {
+    /// Lowercased; `*` for any server.
+    server: String,
+    format: Rc<dyn ChatFormat>,
+}
+
+/// `server` as written in a rule, against the lowercased name and MOTD.
+fn server_matches(server: &str, name: &str, motd: &str) -> bool {
+    server == "*" || name.contains(server) || motd.contains(server)
+}
+
+/// Malformed rules are logged and left out.
+fn parse_rules(value: &str) -> Vec<Rule> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .filter_map(|rule| {
+            let Some((server, format)) = rule.split_once('|') else {
+                warn!(?rule, "chat format rule should be server|format");
+                return None;
+            };
+            match parse_format(format.trim()) {
+                Ok(format) => Some(Rule {
+                    server: server.trim().to_lowercase(),
+                    format,
+                }),
+                Err(e) => {
+                    warn!(?rule, "ignoring chat format rule: {:#}", e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Formats to try for the server called `name` with `motd`: every matching
+/// rule in order, or else the built-in profile for the server.
+fn select(rules: &[Rule], name: &str, motd: &str) -> Vec<Rc<dyn ChatFormat>> {
+    let (name, motd) = (name.to_lowercase(), motd.to_lowercase());
+    let formats: Vec<_> = rules
+        .iter()
+        .filter(|rule| server_matches(&rule.server, &name, &motd))
+        .map(|rule| rule.format.clone())
+        .collect();
+    if !formats.is_empty() {
+        return formats;
+    }
+    let builtin = KNOWN_SERVERS
+        .iter()
+        .find(|(server, _)| server_matches(server, &name, &motd))
+        .map_or("mcgalaxy", |(_, format)| format);
+    vec![parse_format(builtin).expect("built-in formats parse")]
+}
+
+thread_local!(
+    static RULES: RefCell<Vec<Rule>> = Default::default();
+);
+
+// The selection and the server name and MOTD it was made for; redone when
+// either changes, as they do on (re)connecting.
+thread_local!(
+    static ACTIVE: RefCell<Option<((String, String), Vec<Rc<dyn ChatFormat>>)>> =
+        Default::default();
+);
+
+/// Formats for the current server, most specific first.
+pub fn active() -> Vec<Rc<dyn ChatFormat>> {
+    let server = unsafe { (Server.Name.to_string(), Server.MOTD.to_string()) };
+    ACTIVE.with_borrow_mut(|active| {
+        if let Some((for_server, formats)) = active.as_ref() {
+            if *for_server == server {
+                return formats.clone();
+            }
+        }
+        let formats = RULES.with_borrow(|rules| select(rules, &server.0, &server.1));
+        debug!(
+            ?server,
+            formats = ?formats.iter().map(|f| f.describe()).collect::<Vec<_>>(),
+            "selected chat formats"
+        );
+        *active = Some((server, formats.clone()));
+        formats
+    })
+}
+
+pub fn initialize() {
+    let rules = settings::read_option(OPTION_NAME)
+        .map(|value| parse_rules(&value))
+        .unwrap_or_default();
+    debug!(rules = rules.len(), "loaded chat format rules");
+    RULES.with_borrow_mut(|slot| *slot = rules);
+    ACTIVE.take();
+}
+
+pub fn free() {
+    RULES.with_borrow_mut(Vec::clear);
+    ACTIVE.take();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChatFormat, Generic, McGalaxy, Pattern, parse_rules, select};
+
+    fn describe(formats: &[std::rc::Rc<dyn ChatFormat>]) -> Vec<String> {
+        formats.iter().map(|f| f.describe()).collect()
+    }
+
+    #[test]
+    fn mcgalaxy_splits_at_the_first_colon() {
+        assert_eq!(
+            McGalaxy.split("&7Goodly: &fsee: this"),
+            Some(("&7Goodly", "&fsee: this"))
+        );
+        assert_eq!(McGalaxy.split("ab: short"), None);
+    }
+
+    #[test]
+    fn generic_handles_common_layouts() {
+        assert_eq!(Generic.split("<Steve> hello"), Some(("Steve", "hello")));
+        assert_eq!(Generic.split("&e<Steve> a > b"), Some(("Steve", "a > b")));
+        assert_eq!(
+            Generic.split("&7[Admin] &fAlex \u{BB} hi: there"),
+            Some(("&7[Admin] &fAlex", "hi: there"))
+        );
+        assert_eq!(Generic.split("Alex: hi"), Some(("Alex", "hi")));
+        assert_eq!(Generic.split("Server restarting soon"), None);
+    }
+
+    #[test]
+    fn patterns_skip_colors_and_capture_the_holes() {
+        let pattern = Pattern::parse("[{any}] {name} \u{BB} {text}").unwrap();
+        assert_eq!(
+            pattern.split("&8[&cMod&8] &aAlex &7\u{BB} &fhi [there]"),
+            Some(("&aAlex", "&fhi [there]"))
+        );
+        assert_eq!(pattern.split("Alex: hi"), None);
+
+        let pattern = Pattern::parse("{name} whispers: {text}").unwrap();
+        assert_eq!(pattern.split("Bob whispers: psst"), Some(("Bob", "psst")));
+    }
+
+    #[test]
+    fn rejects_bad_patterns() {
+        assert!(Pattern::parse("{name} says").is_err());
+        assert!(Pattern::parse("{name}: {text} {text}").is_err());
+        assert!(Pattern::parse("{who}: {text}").is_err());
+        assert!(Pattern::parse("{name: {text}").is_err());
+    }
+
+    #[test]
+    fn picks_rules_by_server_name_or_motd() {
+        let rules = parse_rules(
+            "Freebuild|<{name}> {text}; broken rule ;*|generic;hax|{nope}: {text};Survival|mcgalaxy",
+        );
+        assert_eq!(rules.len(), 3);
+
+        assert_eq!(
+            describe(&select(&rules, "Best FREEBUILD", "")),
+            ["<{name}> {text}", "generic"]
+        );
+        assert_eq!(
+            describe(&select(&rules, "Other", "welcome to survival")),
+            ["generic", "mcgalaxy"]
+        );
+        assert_eq!(describe(&select(&[], "A server", "")), ["mcgalaxy"]);
+        assert_eq!(describe(&select(&[], "My fCraft server", "")), ["generic"]);
+    }
+}
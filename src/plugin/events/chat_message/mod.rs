@@ -1,6 +1,11 @@
+mod bridge;
+mod format;
+mod nick_match;
+
 use std::{
     cell::{Cell, RefCell},
     collections::HashMap,
+    rc::Rc,
 };
 
 use classicube_helpers::{
@@ -11,7 +16,12 @@ use classicube_helpers::{
 use classicube_sys::{MsgType_MSG_TYPE_NORMAL, Server};
 use tracing::{debug, warn};
 
-use super::player_chat_event::PlayerChatEvent;
+use self::{
+    format::{ChatFormat, leading_color_codes},
+    nick_match::{Candidate, best_match, match_display_name},
+};
+use super::{bridged_chat, ignore_list, player_chat_event::PlayerChatEvent};
+use crate::plugin::rendering;
 
 thread_local!(
     static CHAT_RECEIVED_HANDLER: RefCell<Option<ChatReceivedEventHandler>> = Default::default();
@@ -29,9 +39,10 @@ thread_local!(
 // with its prefix stripped. Keeping the splits lets the bubble render the
 // same break points the server used instead of re-wrapping the join.
 // Mirrors `classicube-cef-plugin/src/chat/mod.rs:25-44` without the
-// `FUTURE_HANDLE` cancel (no async task to abort here).
+// `FUTURE_HANDLE` cancel (no async task to abort here). The speaker is `None`
+// for a bridged line, whose continuations go to `bridged_chat`.
 thread_local!(
-    static LAST_CHAT: RefCell<Option<(u8, Vec<String>)>> = const { RefCell::new(None) };
+    static LAST_CHAT: RefCell<Option<(Option<u8>, Vec<String>)>> = const { RefCell::new(None) };
 );
 
 // Most recent chat-line prefix observed per player id — the `full_nick` slice
@@ -58,6 +69,8 @@ pub fn is_in_whisper_mode() -> bool {
 }
 
 pub fn initialize() {
+    format::initialize();
+    bridge::initialize();
     TAB_LIST.with_borrow_mut(|option| {
         *option = Some(TabList::new());
     });
@@ -73,25 +86,89 @@ pub fn initialize() {
                     return;
                 }
 
-                if let Some(new_state) = detect_whisper_mode_transition(message) {
+                let formats = format::active();
+
+                if let Some(new_state) = formats
+                    .iter()
+                    .find_map(|format| format.whisper_mode_transition(message))
+                {
                     WHISPER_MODE.set(new_state);
                 }
 
-                if let Some(continuation) = is_continuation_message(message) {
+                if let Some((nick, afk)) =
+                    formats.iter().find_map(|format| format.afk_line(message))
+                {
+                    match find_speaker(&nick) {
+                        Some(player_id) => PlayerChatEvent::AfkChanged(afk).emit(player_id),
+                        None => warn!(?nick, "could not resolve player from AFK line"),
+                    }
+                    return;
+                }
+
+                if let Some((nick, ignored)) = formats
+                    .iter()
+                    .find_map(|format| format.ignore_line(message))
+                {
+                    // The confirmation shows the nick; the list is keyed by
+                    // account name. Fall back to the nick for players who
+                    // have already left.
+                    let name = find_speaker(&nick)
+                        .and_then(get_player_name)
+                        .unwrap_or(nick);
+                    if ignored {
+                        if let Err(e) = ignore_list::add(&name) {
+                            warn!(?name, "ignore: {:#}", e);
+                        }
+                    } else {
+                        ignore_list::remove(&name);
+                    }
+                    return;
+                }
+
+                if let Some((player_id, text)) = formats
+                    .iter()
+                    .find_map(|format| format.action(message))
+                    .and_then(|(actor, text)| Some((find_speaker(actor)?, text)))
+                {
+                    LAST_CHAT.with_borrow_mut(|cell| {
+                        *cell = Some((Some(player_id), vec![text.to_string()]));
+                    });
+                    PlayerChatEvent::Action(text.to_string()).emit(player_id);
+                    return;
+                }
+
+                if let Some(continuation) = formats
+                    .iter()
+                    .find_map(|format| format.continuation(message))
+                {
                     let result = LAST_CHAT.with_borrow_mut(|cell| {
                         let (id, lines) = cell.as_mut()?;
                         lines.push(continuation.to_string());
                         Some((*id, lines.clone()))
                     });
-                    let Some((player_id, lines)) = result else {
-                        warn!(?continuation, "continuation with no prior message");
-                        return;
-                    };
-                    PlayerChatEvent::MessageContinuation(lines).emit(player_id);
+                    match result {
+                        Some((Some(player_id), lines)) => {
+                            PlayerChatEvent::MessageContinuation(lines).emit(player_id);
+                        }
+                        Some((None, _)) => {
+                            bridged_chat::extend_last(continuation);
+                        }
+                        None => warn!(?continuation, "continuation with no prior message"),
+                    }
+                    return;
+                }
+
+                if let Some((name, text)) = bridge::detect(message) {
+                    LAST_CHAT.with_borrow_mut(|cell| {
+                        *cell = Some((None, vec![text.clone()]));
+                    });
+                    bridged_chat::push(&name, &text);
                     return;
                 }
 
-                let Some((player_id, said_text, observed_prefix)) = resolve_message(message) else {
+                let Some((player_id, said_text, observed_prefix)) =
+                    resolve_message(&formats, message)
+                else {
                     warn!(?message, "could not resolve player from message");
                     LAST_CHAT.with_borrow_mut(|cell| *cell = None);
                     return;
@@ -103,7 +180,7 @@ pub fn initialize() {
                     });
                 }
                 LAST_CHAT.with_borrow_mut(|cell| {
-                    *cell = Some((player_id, vec![said_text.clone()]));
+                    *cell = Some((Some(player_id), vec![said_text.clone()]));
                 });
                 PlayerChatEvent::Message(said_text).emit(player_id);
             },
@@ -124,6 +201,8 @@ pub fn free() {
     });
     OBSERVED_CHAT_PREFIX.with_borrow_mut(|map| map.clear());
     WHISPER_MODE.set(false);
+    bridge::free();
+    format::free();
 }
 
 /// `> rest of message` → `Some("rest of message")`. Anything else → `None`.
@@ -137,6 +216,20 @@ fn is_continuation_message(message: &str) -> Option<&str> {
     message.strip_prefix("> ")
 }
 
+/// `/me` lines: `*Name does something` (MCGalaxy) or `* Name does
+/// something`. Returns the actor as written, trailing color codes and all,
+/// and what they did. Leading color codes are skipped.
+fn detect_action_line(message: &str) -> Option<(&str, &str)> {
+    let rest = message[leading_color_codes(message)..].strip_prefix('*')?;
+    let rest = rest.strip_prefix(' ').unwrap_or(rest);
+    let (actor, text) = rest.split_once(' ')?;
+    // `*** Announcement ***` and the like.
+    if actor.is_empty() || actor.starts_with('*') || text.trim().is_empty() {
+        return None;
+    }
+    Some((actor, text))
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 enum WhisperKind {
     Incoming,
@@ -149,17 +242,13 @@ enum WhisperKind {
 /// in arbitrary colors (`&9[>] `, `&7[<] `, …), so the color codes are
 /// skipped rather than matched on a specific palette.
 fn detect_whisper_prefix(message: &str) -> Option<(WhisperKind, &str)> {
-    let bytes = message.as_bytes();
-    let mut i = 0;
-    while i + 1 < bytes.len() && bytes[i] == b'&' {
-        i += 2;
-    }
-    let kind = match bytes.get(i..i + 4)? {
+    let rest = &message[leading_color_codes(message)..];
+    let kind = match rest.as_bytes().get(..4)? {
         b"[>] " => WhisperKind::Incoming,
         b"[<] " => WhisperKind::Outgoing,
         _ => return None,
     };
-    Some((kind, &message[i + 4..]))
+    Some((kind, &rest[4..]))
 }
 
 /// Server-emitted toggle messages for auto-whisper mode (MCGalaxy
@@ -169,12 +258,7 @@ fn detect_whisper_prefix(message: &str) -> Option<(WhisperKind, &str)> {
 /// deliberately not matched — when the whisper target logs off mid-mode, the
 /// server keeps `p.whisper = true`, so the local mirror must stay set too.
 fn detect_whisper_mode_transition(message: &str) -> Option<bool> {
-    let bytes = message.as_bytes();
-    let mut i = 0;
-    while i + 1 < bytes.len() && bytes[i] == b'&' {
-        i += 2;
-    }
-    let rest = message.get(i..)?;
+    let rest = &message[leading_color_codes(message)..];
     if rest.starts_with("Auto-whisper enabled. All messages will now be sent to ")
         || rest == "All messages sent will now auto-whisper"
     {
@@ -186,28 +270,117 @@ fn detect_whisper_mode_transition(message: &str) -> Option<bool> {
     }
 }
 
+/// Removes `&X` color codes. Matching on the code's shape rather than the
+/// runtime palette keeps this usable from tests; a stray `&` followed by a
+/// letter in a nick is rare enough not to matter.
+pub fn strip_color_codes(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '&' && chars.peek().is_some_and(char::is_ascii_alphanumeric) {
+            chars.next();
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// MCGalaxy's AFK announcements, color-stripped:
+/// - `/afk`: `-Nick- is AFK <reason>` / `-Nick- is no longer AFK`
+/// - auto-AFK: `Nick is now AFK` / `Nick is no longer AFK`
+///
+/// Returns the nick and whether they went AFK. Chat lines (`Nick: ...`) never
+/// match, so a player typing "is now AFK" doesn't flip anyone's icon.
+fn detect_afk_line(message: &str) -> Option<(String, bool)> {
+    let message = strip_color_codes(message);
+    let (nick, afk) = if let Some(rest) = message.strip_prefix('-') {
+        if let Some(pos) = rest.find("- is no longer AFK") {
+            (&rest[..pos], false)
+        } else if let Some(pos) = rest.find("- is AFK") {
+            (&rest[..pos], true)
+        } else {
+            return None;
+        }
+    } else if let Some(nick) = message.strip_suffix(" is no longer AFK") {
+        (nick, false)
+    } else if let Some(nick) = message.strip_suffix(" is now AFK") {
+        (nick, true)
+    } else {
+        return None;
+    };
+    if nick.is_empty() || nick.contains(": ") {
+        return None;
+    }
+    Some((nick.to_string(), afk))
+}
+
+/// MCGalaxy's `/ignore <player>` confirmations, color-stripped:
+/// `Now ignoring Nick` / `No longer ignoring Nick`. Returns the nick and
+/// whether they're now ignored.
+///
+/// The same command toggles whole categories (`Now ignoring all chat`,
+/// `... IRC chat`, `... titles`); those are told apart by containing a space
+/// or being one of the one-word category names. Nicks with spaces are
+/// therefore missed, which only means falling back to `/client bubbles mute`.
+fn detect_ignore_line(message: &str) -> Option<(String, bool)> {
+    const CATEGORIES: &[&str] = &["titles", "nicks", "8ball", "drawoutput", "irc"];
+
+    let message = strip_color_codes(message);
+    let (nick, ignored) = if let Some(nick) = message.strip_prefix("Now ignoring ") {
+        (nick, true)
+    } else if let Some(nick) = message.strip_prefix("No longer ignoring ") {
+        (nick, false)
+    } else {
+        return None;
+    };
+    let nick = nick.trim();
+    if nick.is_empty()
+        || nick.contains(' ')
+        || CATEGORIES.iter().any(|c| c.eq_ignore_ascii_case(nick))
+    {
+        return None;
+    }
+    Some((nick.to_string(), ignored))
+}
+
+fn find_player_id_by_nick(nick: &str) -> Option<u8> {
+    if unsafe { Server.IsSinglePlayer } != 0 {
+        return None;
+    }
+    TAB_LIST.with_borrow(|cell| {
+        cell.as_ref()?
+            .find_entry_by_nick_name(nick)
+            .and_then(|entry| entry.upgrade())
+            .map(|entry| entry.get_id())
+    })
+}
+
 /// Returns `(player_id, said_text, observed_prefix)` for a non-continuation
 /// chat line. `observed_prefix` is the full nick slice (color + title + name)
 /// to cache for the typing-preview wrap budget, set only on regular chat —
 /// whispers leave it `None` because the `[>] Sender` / `[<] Recipient` prefix
 /// doesn't match what the server prepends to that player's regular chat.
-fn resolve_message(message: &str) -> Option<(u8, String, Option<String>)> {
-    if let Some((kind, remainder)) = detect_whisper_prefix(message) {
+fn resolve_message(
+    formats: &[Rc<dyn ChatFormat>],
+    message: &str,
+) -> Option<(u8, String, Option<String>)> {
+    if let Some((kind, remainder)) = formats.iter().find_map(|format| format.whisper(message)) {
         match kind {
             // Drop the recipient nick; we are the speaker.
             WhisperKind::Outgoing => {
-                let pos = remainder.find(": ")?;
-                Some((ENTITY_SELF_ID, remainder[pos + 2..].to_string(), None))
+                let (_, said_text) = formats.iter().find_map(|format| format.split(remainder))?;
+                Some((ENTITY_SELF_ID, said_text.to_string(), None))
             }
             // Reuse the regular parser on the post-marker slice for the
-            // colon split + tab-list lookup (which color-strips internally).
+            // split + tab-list lookup (which color-strips internally).
             WhisperKind::Incoming => {
-                let (player_id, _, said_text) = find_player_from_message(remainder)?;
+                let (player_id, _, said_text) = find_player_from_message(formats, remainder)?;
                 Some((player_id, said_text.to_string(), None))
             }
         }
     } else {
-        let (player_id, full_nick, said_text) = find_player_from_message(message)?;
+        let (player_id, full_nick, said_text) = find_player_from_message(formats, message)?;
         Some((
             player_id,
             said_text.to_string(),
@@ -232,6 +405,59 @@ pub fn get_nick_name(id: u8) -> Option<String> {
     })
 }
 
+/// Account name (no colors or titles) for `id`, as typed in commands.
+pub fn get_player_name(id: u8) -> Option<String> {
+    if unsafe { Server.IsSinglePlayer } != 0 {
+        return None;
+    }
+    TAB_LIST.with_borrow(|cell| {
+        cell.as_ref()?
+            .get(id)
+            .and_then(|w| w.upgrade())
+            .map(|entry| entry.get_player_name())
+    })
+}
+
+/// Tab-list id of the player whose account name is `name`, ignoring case.
+pub fn find_player_id_by_name(name: &str) -> Option<u8> {
+    if unsafe { Server.IsSinglePlayer } != 0 {
+        return None;
+    }
+    TAB_LIST.with_borrow(|cell| {
+        cell.as_ref()?
+            .get_all()
+            .into_iter()
+            .filter_map(|(id, entry)| Some((id, entry.upgrade()?)))
+            .find(|(_, entry)| entry.get_player_name().eq_ignore_ascii_case(name))
+            .map(|(id, _)| id)
+    })
+}
+
+/// Tab-list id of the player `name` refers to as typed in a command: their
+/// nick (colors and case aside) or their account name.
+pub fn find_player_id_by_typed_name(name: &str) -> Option<u8> {
+    if unsafe { Server.IsSinglePlayer } != 0 {
+        return None;
+    }
+    let name = name.trim();
+    find_player_id_by_nick(name)
+        .or_else(|| {
+            TAB_LIST.with_borrow(|cell| {
+                cell.as_ref()?
+                    .get_all()
+                    .into_iter()
+                    .filter_map(|(id, entry)| Some((id, entry.upgrade()?)))
+                    .find(|(_, entry)| {
+                        strip_color_codes(&entry.get_nick_name())
+                            .trim()
+                            .eq_ignore_ascii_case(name)
+                    })
+                    .map(|(id, _)| id)
+            })
+        })
+        .or_else(|| find_player_id_by_name(name))
+}
+
 /// Cached chat-line prefix the server actually prepended for `id`, captured
 /// from the most recent chat message that player sent. Preferred over the
 /// tab-list nick for sizing the typing-preview wrap since some servers add
@@ -241,45 +467,88 @@ pub fn get_chat_prefix(id: u8) -> Option<String> {
     OBSERVED_CHAT_PREFIX.with_borrow(|map| map.get(&id).cloned())
 }
 
-fn find_player_from_message(full_msg: &str) -> Option<(u8, Option<&str>, &str)> {
+/// Everyone on the tab list with their nick and account name, plus
+/// `display_names` of the entities in the world.
+fn speaker_candidates(display_names: Vec<(u8, String)>) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = TAB_LIST.with_borrow(|cell| {
+        let Some(tab_list) = cell.as_ref() else {
+            return Vec::new();
+        };
+        tab_list
+            .get_all()
+            .into_iter()
+            .filter_map(|(id, entry)| {
+                let entry = entry.upgrade()?;
+                Some(Candidate {
+                    id,
+                    names: vec![entry.get_nick_name(), entry.get_player_name()],
+                })
+            })
+            .collect()
+    });
+    for (id, name) in display_names {
+        match candidates.iter_mut().find(|candidate| candidate.id == id) {
+            Some(candidate) => candidate.names.push(name),
+            None => candidates.push(Candidate {
+                id,
+                names: vec![name],
+            }),
+        }
+    }
+    candidates
+}
+
+/// Entity id for a speaker as the server wrote them. The exact nick lookup
+/// covers plain MCGalaxy chat. Bots and NPCs aren't on the tab list, so next
+/// comes an exact match on the name tags of entities without a tab-list
+/// entry; titles, team tags and rank glyphs fall through to `nick_match`.
+fn find_speaker(full_nick: &str) -> Option<u8> {
+    if unsafe { Server.IsSinglePlayer } != 0 {
+        return None;
+    }
+    find_player_id_by_nick(full_nick).or_else(|| {
+        let display_names = rendering::display_names();
+        let listed: Vec<u8> = TAB_LIST.with_borrow(|cell| {
+            cell.as_ref()
+                .map(|tab_list| tab_list.get_all().into_iter().map(|(id, _)| id).collect())
+                .unwrap_or_else(Vec::new)
+        });
+        if let Some(id) = match_display_name(full_nick, &display_names, &listed) {
+            debug!(?full_nick, ?id, "speaker matched by name tag");
+            return Some(id);
+        }
+        let id = best_match(full_nick, &speaker_candidates(display_names));
+        debug!(?full_nick, ?id, "fuzzy speaker match");
+        id
+    })
+}
+
+/// The first of `formats` that splits `full_msg` into a player or bot we can
+/// find and their text.
+fn find_player_from_message<'a>(
+    formats: &[Rc<dyn ChatFormat>],
+    full_msg: &'a str,
+) -> Option<(u8, Option<&'a str>, &'a str)> {
     if unsafe { Server.IsSinglePlayer } != 0 {
         // in singleplayer there is no tab list, even self id infos are null
 
         return Some((ENTITY_SELF_ID, None, full_msg));
     }
 
-    // find colon from the left
-    let pos = full_msg
-        .find(": ")
-        .and_then(|pos| if pos >= 3 { Some(pos) } else { None })?;
-
-    // > &fasdfasdf
-
-    // &]SpiralP
-    let full_nick = &full_msg.get(..pos)?; // left without colon
-
-    // &faaa
-    let said_text = &full_msg.get((pos + 2)..)?; // right without colon
-
-    debug!(?full_nick, ?said_text);
-    // TODO title is [ ] before nick, team is < > before nick, also there are rank
-    // symbols? &f┬ &f♂&6 Goodly: &fhi
-
-    TAB_LIST.with(move |cell| {
-        let tab_list = &*cell.borrow();
-        tab_list
-            .as_ref()
-            .unwrap()
-            .find_entry_by_nick_name(full_nick)
-            .and_then(|entry| entry.upgrade())
-            .map(|entry| (entry.get_id(), Some(*full_nick), *said_text))
+    formats.iter().find_map(|format| {
+        let (full_nick, said_text) = format.split(full_msg)?;
+        debug!(?full_nick, ?said_text, format = format.describe());
+        let player_id = find_speaker(full_nick)?;
+        Some((player_id, Some(full_nick), said_text))
     })
 }
 
 #[cfg(test)]
 mod tests {
     use super::{
-        WhisperKind, detect_whisper_mode_transition, detect_whisper_prefix, is_continuation_message,
+        WhisperKind, detect_action_line, detect_afk_line, detect_ignore_line,
+        detect_whisper_mode_transition, detect_whisper_prefix, is_continuation_message,
+        strip_color_codes,
     };
 
     #[test]
@@ -301,6 +570,21 @@ mod tests {
         assert_eq!(is_continuation_message(">no space"), None);
     }
 
+    #[test]
+    fn detects_action_lines() {
+        assert_eq!(
+            detect_action_line("&a*Goodly&a waves hello"),
+            Some(("Goodly&a", "waves hello"))
+        );
+        assert_eq!(
+            detect_action_line("* Alex sits down"),
+            Some(("Alex", "sits down"))
+        );
+        assert_eq!(detect_action_line("&c*** Server restarting ***"), None);
+        assert_eq!(detect_action_line("*Lonely"), None);
+        assert_eq!(detect_action_line("&7Player: &f*hugs* you"), None);
+    }
+
     #[test]
     fn detects_incoming_whisper() {
         assert_eq!(
@@ -400,4 +684,64 @@ mod tests {
         assert_eq!(detect_whisper_mode_transition("> &fcontinuation"), None);
         assert_eq!(detect_whisper_mode_transition(""), None);
     }
+
+    #[test]
+    fn strips_color_codes() {
+        assert_eq!(strip_color_codes("&aBob&e- is AFK"), "Bob- is AFK");
+        assert_eq!(strip_color_codes("no codes"), "no codes");
+        assert_eq!(strip_color_codes("trailing &"), "trailing &");
+        assert_eq!(strip_color_codes("& spaced"), "& spaced");
+    }
+
+    #[test]
+    fn detects_ignore_confirmations() {
+        assert_eq!(
+            detect_ignore_line("&cNow ignoring &aSpammer"),
+            Some(("Spammer".to_string(), true))
+        );
+        assert_eq!(
+            detect_ignore_line("&aNo longer ignoring &aSpammer"),
+            Some(("Spammer".to_string(), false))
+        );
+    }
+
+    #[test]
+    fn ignores_ignore_category_toggles() {
+        assert_eq!(detect_ignore_line("&cNow ignoring all chat"), None);
+        assert_eq!(detect_ignore_line("&aNo longer ignoring IRC chat"), None);
+        assert_eq!(detect_ignore_line("&cNow ignoring titles"), None);
+        assert_eq!(detect_ignore_line("Bob: Now ignoring Alice"), None);
+    }
+
+    #[test]
+    fn detects_afk_command_lines() {
+        assert_eq!(
+            detect_afk_line("-&aBob&S- is AFK getting food"),
+            Some(("Bob".to_string(), true))
+        );
+        assert_eq!(
+            detect_afk_line("&e-&aBob&e- is no longer AFK"),
+            Some(("Bob".to_string(), false))
+        );
+    }
+
+    #[test]
+    fn detects_auto_afk_lines() {
+        assert_eq!(
+            detect_afk_line("&aBob &Sis now AFK"),
+            Some(("Bob".to_string(), true))
+        );
+        assert_eq!(
+            detect_afk_line("&aBob &Sis no longer AFK"),
+            Some(("Bob".to_string(), false))
+        );
+    }
+
+    #[test]
+    fn ignores_afk_phrases_in_chat() {
+        assert_eq!(detect_afk_line("&7Player: &fhe is now AFK"), None);
+        assert_eq!(detect_afk_line("&7Player: &f-x- is AFK"), None);
+        assert_eq!(detect_afk_line(" is now AFK"), None);
+        assert_eq!(detect_afk_line("&7Player: &fhi"), None);
+    }
 }
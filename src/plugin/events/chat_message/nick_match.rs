@@ -0,0 +1,245 @@
+//! Works out who a chat line's speaker is when the slice before the text
+//! isn't a tab-list nick as-is: servers put titles (`[Admin]`), team tags
+//! (`<Red>`) and rank glyphs (`&f┬ &f♂&6 Goodly`) in front, which the tab
+//! list's exact lookup never matches.
+//!
+//! Both sides are boiled down to a bare name, then every known player is
+//! scored by how well any of their names (tab-list nick, account name,
+//! entity display name) matches. The best clear winner is the speaker.
+
+use super::strip_color_codes;
+
+/// Scores below this aren't a match at all.
+const MIN_SCORE: u32 = 30;
+
+/// Names one player goes by.
+pub struct Candidate {
+    pub id: u8,
+    pub names: Vec<String>,
+}
+
+/// Characters a name can start with; anything else in front is decoration.
+fn starts_name(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// ClassiCube accounts on MCGalaxy end in `+`.
+fn ends_name(c: char) -> bool {
+    starts_name(c) || c == '+'
+}
+
+/// `text` without `[...]`, `<...>`, `(...)` and `{...}` groups. An unclosed
+/// bracket is left alone.
+fn strip_brackets(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(open) = rest.find(['[', '<', '(', '{']) {
+        let close = match rest[open..].chars().next() {
+            Some('[') => ']',
+            Some('<') => '>',
+            Some('(') => ')',
+            _ => '}',
+        };
+        let Some(len) = rest[open..].find(close) else {
+            break;
+        };
+        out.push_str(&rest[..open]);
+        out.push(' ');
+        rest = &rest[open + len + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The bare name in a decorated nick: colors, bracketed titles and team
+/// tags dropped, then the last word with any glyphs around it trimmed.
+pub fn core_name(nick: &str) -> String {
+    let stripped = strip_brackets(&strip_color_codes(nick));
+    stripped
+        .split_whitespace()
+        .map(|word| {
+            word.trim_start_matches(|c| !starts_name(c))
+                .trim_end_matches(|c| !ends_name(c))
+        })
+        .rfind(|word| !word.is_empty())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// The one entity whose name tag reads `full_nick`, colors and case aside.
+/// Only entities missing from the tab list (`listed`) count: players are
+/// found by nick, and one whose tag reads like `Server` mustn't claim server
+/// lines. Empty tags (hidden names) never match.
+pub fn match_display_name(
+    full_nick: &str,
+    display_names: &[(u8, String)],
+    listed: &[u8],
+) -> Option<u8> {
+    let wanted = strip_color_codes(full_nick);
+    let wanted = wanted.trim();
+    if wanted.is_empty() {
+        return None;
+    }
+    let mut matches = display_names
+        .iter()
+        .filter(|(id, _)| !listed.contains(id))
+        .filter(|(_, name)| strip_color_codes(name).trim().eq_ignore_ascii_case(wanted))
+        .map(|(id, _)| *id);
+    let id = matches.next()?;
+    matches.next().is_none().then_some(id)
+}
+
+/// How well the chat line's `full_nick` matches a player known as `name`.
+fn score(full_nick: &str, core: &str, name: &str) -> u32 {
+    if strip_color_codes(full_nick) == strip_color_codes(name) {
+        return 100;
+    }
+    let other = core_name(name);
+    if core.is_empty() || other.is_empty() {
+        return 0;
+    }
+    if core == other {
+        return 80;
+    }
+    // No partial matches: `Server: ...` must never land on `ServerAdmin`.
+    if core.eq_ignore_ascii_case(&other) {
+        return 70;
+    }
+    0
+}
+
+/// The player `full_nick` most likely is. `None` when nobody scores well
+/// enough, or two players tie for best. Only nicks with brackets, glyphs or
+/// tags around the name are guessed at; a word that's merely colored, like
+/// `&eServer: restarting`, is a server, plugin or bridge line.
+pub fn best_match(full_nick: &str, candidates: &[Candidate]) -> Option<u8> {
+    let core = core_name(full_nick);
+    if core == strip_color_codes(full_nick).trim() {
+        return None;
+    }
+    let mut best: Option<(u32, u8)> = None;
+    let mut tied = false;
+    for candidate in candidates {
+        let Some(score) = candidate
+            .names
+            .iter()
+            .map(|name| score(full_nick, &core, name))
+            .max()
+            .filter(|&score| score >= MIN_SCORE)
+        else {
+            continue;
+        };
+        match best {
+            Some((top, _)) if score < top => {}
+            Some((top, id)) if score == top => tied |= id != candidate.id,
+            _ => {
+                best = Some((score, candidate.id));
+                tied = false;
+            }
+        }
+    }
+    if tied { None } else { best.map(|(_, id)| id) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Candidate, best_match, core_name, match_display_name};
+
+    fn candidate(id: u8, names: &[&str]) -> Candidate {
+        Candidate {
+            id,
+            names: names.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn strips_titles_teams_and_glyphs() {
+        assert_eq!(core_name("&f\u{252C} &f\u{2642}&6 Goodly"), "Goodly");
+        assert_eq!(core_name("&8[&cAdmin&8] &aAlex"), "Alex");
+        assert_eq!(core_name("&c<Red> &fBob+"), "Bob+");
+        assert_eq!(core_name("~*Star_1*~"), "Star_1");
+        assert_eq!(core_name("[Title] Sir (AFK)"), "Sir");
+        assert_eq!(core_name("[unclosed Name"), "Name");
+        assert_eq!(core_name("&7[Mod]"), "");
+    }
+
+    #[test]
+    fn finds_decorated_speakers() {
+        let players = [
+            candidate(1, &["&6Goodly", "Goodly"]),
+            candidate(2, &["&aAlex", "alex"]),
+            candidate(3, &["&fAlexander", "Alexander"]),
+        ];
+        assert_eq!(
+            best_match("&f\u{252C} &f\u{2642}&6 Goodly", &players),
+            Some(1)
+        );
+        assert_eq!(best_match("&8[Admin] &aALEX", &players), Some(2));
+        // Exact beats a longer name sharing the start.
+        assert_eq!(best_match("[VIP] Alex", &players), Some(2));
+        // A cut-short nick isn't guessed at.
+        assert_eq!(best_match("[VIP] Alexand", &players), None);
+        assert_eq!(best_match("Nobody", &players), None);
+        assert_eq!(best_match("Alexa", &players), None);
+        assert_eq!(best_match("[Server]", &players), None);
+    }
+
+    #[test]
+    fn leaves_undecorated_prefixes_alone() {
+        let players = [
+            candidate(1, &["&cServerAdmin", "ServerAdmin"]),
+            candidate(2, &["&aAlex", "Alex"]),
+        ];
+        assert_eq!(best_match("Server", &players), None);
+        assert_eq!(best_match("ALEX", &players), None);
+        assert_eq!(best_match("&8[&aVIP&8] &aALEX", &players), Some(2));
+    }
+
+    #[test]
+    fn colored_server_lines_claim_nobody() {
+        let players = [
+            candidate(1, &["&cServerAdmin", "ServerAdmin"]),
+            candidate(2, &["&aHintsman", "Hintsman"]),
+        ];
+        assert_eq!(best_match("&eServer", &players), None);
+        assert_eq!(best_match("&cHint", &players), None);
+        assert_eq!(best_match("&S&eServer", &players), None);
+        // Decorated, but still only the start of a name.
+        assert_eq!(best_match("&7[&cAdmin&7] Server", &players), None);
+        assert_eq!(best_match("&7[&cAdmin&7] ServerAdmin", &players), Some(1));
+    }
+
+    #[test]
+    fn finds_bots_by_name_tag() {
+        let entities = [
+            (3, "&eShopkeeper".to_string()),
+            (7, "&cGuard".to_string()),
+            (8, "&cGuard".to_string()),
+            (9, String::new()),
+        ];
+        assert_eq!(match_display_name("&eSHOPKEEPER", &entities, &[]), Some(3));
+        // Two identical guards could be either.
+        assert_eq!(match_display_name("&cGuard", &entities, &[]), None);
+        assert_eq!(match_display_name("&f", &entities, &[]), None);
+        assert_eq!(match_display_name("Stranger", &entities, &[]), None);
+    }
+
+    #[test]
+    fn name_tags_of_listed_players_are_ignored() {
+        let entities = [(2, "&fServer".to_string()), (7, "&cGuard".to_string())];
+        assert_eq!(match_display_name("Server", &entities, &[2]), None);
+        assert_eq!(match_display_name("&cGuard", &entities, &[2]), Some(7));
+        // The second guard is a player dressed up as one.
+        let entities = [(7, "&cGuard".to_string()), (8, "&cGuard".to_string())];
+        assert_eq!(match_display_name("&cGuard", &entities, &[8]), Some(7));
+    }
+
+    #[test]
+    fn gives_up_on_ties() {
+        let players = [candidate(1, &["&aSam"]), candidate(2, &["&cSAM"])];
+        assert_eq!(best_match("[Team] sam", &players), None);
+        // The same player matching through two names isn't a tie.
+        let players = [candidate(1, &["&aSam", "Sam"]), candidate(2, &["Bob"])];
+        assert_eq!(best_match("[Team] Sam", &players), Some(1));
+    }
+}
@@ -0,0 +1,403 @@
+//! Recent messages per player, kept after their bubbles fly away. Keyed by
+//! lowercased account name like the ignore list, so it survives the entity
+//! id being reused. `/client bubbles history <player>` opens a tall bubble
+//! above that player showing a page of it; `up`/`down` scroll and it closes
+//! by itself after a while. Players who aren't in the world get the page in
+//! chat instead.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use super::{
+    chat_message::{find_player_id_by_typed_name, get_player_name},
+    player_chat_event::PlayerChatEvent,
+};
+use crate::plugin::rendering;
+
+/// Messages kept per player; a message may span several lines.
+const MAX_MESSAGES: usize = 50;
+/// Players kept; the one who spoke longest ago goes first.
+const MAX_PLAYERS: usize = 100;
+/// Lines shown at once in the history bubble.
+const PAGE_LINES: usize = 8;
+/// Idle time before an open history bubble closes.
+const VIEW_TIMEOUT: Duration = Duration::from_secs(20);
+
+struct PlayerHistory {
+    /// Oldest first; each entry is one message's server lines and whether
+    /// it was a `/me` action.
+    messages: VecDeque<(Vec<String>, bool)>,
+    last_message: Instant,
+}
+
+#[derive(Default)]
+struct History {
+    players: HashMap<String, PlayerHistory>,
+}
+
+impl History {
+    fn record(&mut self, name: &str, event: &PlayerChatEvent, now: Instant) {
+        let name = name.to_lowercase();
+        match event {
+            PlayerChatEvent::Message(text) => self.push(name, text, false, now),
+            PlayerChatEvent::Action(text) => self.push(name, text, true, now),
+
+            // Carries the whole message so far, first line included.
+            PlayerChatEvent::MessageContinuation(lines) => {
+                if let Some((last, _)) = self
+                    .players
+                    .get_mut(&name)
+                    .and_then(|player| player.messages.back_mut())
+                {
+                    last.clone_from(lines);
+                }
+            }
+
+            PlayerChatEvent::PresenceChanged(_) | PlayerChatEvent::AfkChanged(_) => {}
+        }
+    }
+
+    fn push(&mut self, name: String, text: &str, action: bool, now: Instant) {
+        if !self.players.contains_key(&name) && self.players.len() >= MAX_PLAYERS {
+            let oldest = self
+                .players
+                .iter()
+                .min_by_key(|(_, player)| player.last_message)
+                .map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {
+                self.players.remove(&oldest);
+            }
+        }
+        let player = self.players.entry(name).or_insert_with(|| PlayerHistory {
+            messages: VecDeque::new(),
+            last_message: now,
+        });
+        if player.messages.len() >= MAX_MESSAGES {
+            player.messages.pop_front();
+        }
+        player.messages.push_back((vec![text.to_string()], action));
+        player.last_message = now;
+    }
+
+    /// Oldest first, with actions starting `* `.
+    fn lines(&self, name: &str) -> Vec<String> {
+        self.players
+            .get(&name.to_lowercase())
+            .map(|player| {
+                player
+                    .messages
+                    .iter()
+                    .flat_map(|(lines, action)| {
+                        lines.iter().enumerate().map(move |(i, line)| {
+                            if *action && i == 0 {
+                                format!("* {line}")
+                            } else {
+                                line.clone()
+                            }
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// `scroll` lines up from the newest, clamped so a full page stays in view.
+fn page(lines: &[String], scroll: usize) -> (&[String], usize) {
+    let max_scroll = lines.len().saturating_sub(PAGE_LINES);
+    let scroll = scroll.min(max_scroll);
+    let end = lines.len() - scroll;
+    let start = end.saturating_sub(PAGE_LINES);
+    (&lines[start..end], scroll)
+}
+
+struct View {
+    name: String,
+    /// Lines up from the newest.
+    scroll: usize,
+    touched: Instant,
+}
+
+/// What the open view shows.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ViewPage {
+    /// Lowercased.
+    pub name: String,
+    /// Header first.
+    pub lines: Vec<String>,
+}
+
+thread_local!(
+    static HISTORY: RefCell<History> = Default::default();
+);
+
+thread_local!(
+    static VIEW: RefCell<Option<View>> = const { RefCell::new(None) };
+);
+
+// Bumped whenever the view or the history under it changes, so every
+// bubble asking for the page in a frame shares one build of it.
+thread_local!(
+    static VIEW_GENERATION: Cell<u32> = const { Cell::new(0) };
+);
+
+thread_local!(
+    static VIEW_PAGE: RefCell<Option<(u32, Option<Rc<ViewPage>>)>> = const { RefCell::new(None) };
+);
+
+fn view_changed() {
+    VIEW_GENERATION.set(VIEW_GENERATION.get().wrapping_add(1));
+}
+
+/// Called for every event that made it past the ignore list.
+pub fn record(entity_id: u8, event: &PlayerChatEvent) {
+    if !matches!(
+        event,
+        PlayerChatEvent::Message(_)
+            | PlayerChatEvent::MessageContinuation(_)
+            | PlayerChatEvent::Action(_)
+    ) {
+        return;
+    }
+    let Some(name) = get_player_name(entity_id) else {
+        return;
+    };
+    HISTORY.with_borrow_mut(|history| history.record(&name, event, Instant::now()));
+    view_changed();
+}
+
+/// What `open` did.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Opened {
+    /// The bubble is up over them, showing the newest of this many lines.
+    Bubble(usize),
+    /// There's no entity to hang a bubble on, so here's their newest page.
+    Page(Vec<String>),
+}
+
+/// Opens the history bubble over the player `name` (nick or account name)
+/// at the newest lines. `None` if they haven't said anything.
+pub fn open(name: &str) -> Option<Opened> {
+    let id = find_player_id_by_typed_name(name);
+    let account = id
+        .and_then(get_player_name)
+        .unwrap_or_else(|| name.trim().to_string());
+    open_for(&account, id.is_some_and(rendering::has_bubble))
+}
+
+/// `open` once `account` is known, and whether they're here to show it over.
+fn open_for(account: &str, in_world: bool) -> Option<Opened> {
+    let lines = HISTORY.with_borrow(|history| history.lines(account));
+    if lines.is_empty() {
+        return None;
+    }
+    if !in_world {
+        return Some(Opened::Page(page_lines(&lines, 0)));
+    }
+    VIEW.set(Some(View {
+        name: account.to_lowercase(),
+        scroll: 0,
+        touched: Instant::now(),
+    }));
+    view_changed();
+    Some(Opened::Bubble(lines.len()))
+}
+
+/// Scrolls the open view by pages, positive being older. Returns the name
+/// and the first and last line shown (1-based, oldest first) and the total.
+pub fn scroll(pages: isize) -> Option<(String, usize, usize, usize)> {
+    view_changed();
+    VIEW.with_borrow_mut(|view| {
+        let view = view.as_mut()?;
+        let lines = HISTORY.with_borrow(|history| history.lines(&view.name));
+        let wanted = view
+            .scroll
+            .saturating_add_signed(pages.saturating_mul(PAGE_LINES as isize));
+        let (shown, scroll) = page(&lines, wanted);
+        view.scroll = scroll;
+        view.touched = Instant::now();
+        let last = lines.len() - scroll;
+        Some((view.name.clone(), last - shown.len() + 1, last, lines.len()))
+    })
+}
+
+pub fn close() -> bool {
+    view_changed();
+    VIEW.take().is_some()
+}
+
+/// The open view's page, built once per change. Closes the view once it's
+/// been idle too long.
+pub fn current_view() -> Option<Rc<ViewPage>> {
+    if VIEW.with_borrow(|view| {
+        view.as_ref()
+            .is_some_and(|view| view.touched.elapsed() > VIEW_TIMEOUT)
+    }) {
+        close();
+    }
+    let generation = VIEW_GENERATION.get();
+    VIEW_PAGE.with_borrow_mut(|cache| {
+        if let Some((_, page)) = cache.as_ref().filter(|(built, _)| *built == generation) {
+            return page.clone();
+        }
+        let page = build_view_page().map(Rc::new);
+        *cache = Some((generation, page.clone()));
+        page
+    })
+}
+
+fn build_view_page() -> Option<ViewPage> {
+    VIEW.with_borrow(|view| {
+        let view = view.as_ref()?;
+        let lines = HISTORY.with_borrow(|history| history.lines(&view.name));
+        Some(ViewPage {
+            name: view.name.clone(),
+            lines: page_lines(&lines, view.scroll),
+        })
+    })
+}
+
+/// The page `scroll` lines up from the newest, header first.
+fn page_lines(lines: &[String], scroll: usize) -> Vec<String> {
+    let (shown, scroll) = page(lines, scroll);
+    let mut out = Vec::with_capacity(shown.len() + 1);
+    out.push(if scroll == 0 {
+        format!("&7History ({})", lines.len())
+    } else {
+        format!("&7History ({}, {scroll} newer)", lines.len())
+    });
+    out.extend(shown.iter().map(|line| format!("&f{line}")));
+    out
+}
+
+pub fn free() {
+    VIEW.take();
+    VIEW_PAGE.take();
+    HISTORY.with_borrow_mut(|history| history.players.clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        time::{Duration, Instant},
+    };
+
+    use super::{
+        HISTORY, History, MAX_MESSAGES, MAX_PLAYERS, Opened, PAGE_LINES, close, current_view,
+        open_for, page, scroll,
+    };
+    use crate::plugin::events::player_chat_event::PlayerChatEvent;
+
+    fn message(text: &str) -> PlayerChatEvent {
+        PlayerChatEvent::Message(text.to_string())
+    }
+
+    #[test]
+    fn continuations_replace_the_last_message() {
+        let mut history = History::default();
+        let now = Instant::now();
+        history.record("Goodly", &message("hi"), now);
+        history.record("goodly", &message("a long one"), now);
+        history.record(
+            "GOODLY",
+            &PlayerChatEvent::MessageContinuation(vec!["a long one".into(), "continued".into()]),
+            now,
+        );
+        history.record("goodly", &PlayerChatEvent::AfkChanged(true), now);
+        history.record("goodly", &PlayerChatEvent::Action("waves".into()), now);
+        history.record(
+            "goodly",
+            &PlayerChatEvent::MessageContinuation(vec!["waves".into(), "at everyone".into()]),
+            now,
+        );
+        assert_eq!(
+            history.lines("Goodly"),
+            ["hi", "a long one", "continued", "* waves", "at everyone"]
+        );
+        assert!(history.lines("someone").is_empty());
+    }
+
+    #[test]
+    fn bounded_per_player_and_in_players() {
+        let mut history = History::default();
+        let start = Instant::now();
+        for i in 0..MAX_MESSAGES + 5 {
+            history.record("chatty", &message(&i.to_string()), start);
+        }
+        let lines = history.lines("chatty");
+        assert_eq!(lines.len(), MAX_MESSAGES);
+        assert_eq!(lines[0], "5");
+
+        for i in 0..MAX_PLAYERS {
+            let at = start + Duration::from_secs(i as u64 + 1);
+            history.record(&format!("p{i}"), &message("x"), at);
+        }
+        // "chatty" spoke first, so made room.
+        assert!(history.lines("chatty").is_empty());
+        assert_eq!(history.players.len(), MAX_PLAYERS);
+    }
+
+    #[test]
+    fn pages_from_the_newest_and_clamps_scrolling() {
+        let lines: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+        let (shown, scroll) = page(&lines, 0);
+        assert_eq!(shown, &lines[20 - PAGE_LINES..]);
+        assert_eq!(scroll, 0);
+
+        let (shown, scroll) = page(&lines, PAGE_LINES);
+        assert_eq!(shown, &lines[20 - 2 * PAGE_LINES..20 - PAGE_LINES]);
+        assert_eq!(scroll, PAGE_LINES);
+
+        let (shown, scroll) = page(&lines, 1000);
+        assert_eq!(shown, &lines[..PAGE_LINES]);
+        assert_eq!(scroll, 20 - PAGE_LINES);
+
+        let short = vec!["only".to_string()];
+        assert_eq!(page(&short, 3), (&short[..], 0));
+    }
+
+    #[test]
+    fn view_page_is_built_once_per_change() {
+        HISTORY.with_borrow_mut(|history| {
+            for i in 0..PAGE_LINES * 2 {
+                history.record("Ann", &message(&i.to_string()), Instant::now());
+            }
+        });
+        assert_eq!(open_for("ann", true), Some(Opened::Bubble(PAGE_LINES * 2)));
+
+        let first = current_view().unwrap();
+        assert_eq!(first.name, "ann");
+        assert_eq!(first.lines.len(), PAGE_LINES + 1);
+        assert!(Rc::ptr_eq(&first, &current_view().unwrap()));
+
+        scroll(1);
+        let scrolled = current_view().unwrap();
+        assert!(!Rc::ptr_eq(&first, &scrolled));
+        assert_ne!(first.lines, scrolled.lines);
+
+        close();
+        assert!(current_view().is_none());
+    }
+
+    #[test]
+    fn absent_players_get_the_page_in_chat() {
+        HISTORY.with_borrow_mut(|history| {
+            for i in 0..PAGE_LINES + 2 {
+                history.record("Gone", &message(&i.to_string()), Instant::now());
+            }
+        });
+        let Some(Opened::Page(lines)) = open_for("gone", false) else {
+            panic!("expected the page in chat");
+        };
+        assert_eq!(lines.len(), PAGE_LINES + 1);
+        assert_eq!(lines.last().unwrap(), &format!("&f{}", PAGE_LINES + 1));
+        // Nothing to draw it on, so no view either.
+        assert!(current_view().is_none());
+        assert_eq!(open_for("nobody", false), None);
+    }
+}
@@ -0,0 +1,169 @@
+//! Players whose bubbles we never show. Keyed by lowercased account name,
+//! since entity ids get reused as players come and go, and saved as a
+//! comma-separated `chatbubbles-ignored` option so it survives restarts.
+//! Bots and NPCs have no account, so they go by their name tag instead.
+//!
+//! Fed by `/client bubbles mute` and by MCGalaxy's `/ignore` confirmations,
+//! so a server-side ignore hides bubbles too.
+
+use std::{cell::RefCell, collections::BTreeSet};
+
+use anyhow::{Result, bail};
+use classicube_helpers::entities::ENTITY_SELF_ID;
+use tracing::{debug, warn};
+
+use super::{
+    chat_message::{find_player_id_by_name, get_player_name, strip_color_codes},
+    player_chat_event::listener,
+};
+use crate::plugin::{
+    rendering,
+    settings::{self, OPTION_CAPACITY},
+};
+
+const OPTION_NAME: &str = "ignored";
+
+thread_local!(
+    static IGNORED: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+);
+
+fn parse(value: &str) -> BTreeSet<String> {
+    value
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+fn serialize(names: &BTreeSet<String>) -> String {
+    names.iter().cloned().collect::<Vec<_>>().join(",")
+}
+
+fn save() {
+    let value = IGNORED.with_borrow(serialize);
+    if value.len() >= OPTION_CAPACITY {
+        warn!(
+            len = value.len(),
+            "ignore list is too long to load back in full next session"
+        );
+    }
+    settings::write_option(OPTION_NAME, value);
+}
+
+/// What an entity is muted by: the account name of a player on the tab
+/// list, or else the name tag of a bot or NPC.
+fn mute_key(account_name: Option<String>, name_tag: Option<String>) -> Option<String> {
+    account_name
+        .map(|name| name.to_lowercase())
+        .or_else(|| name_tag.map(|tag| strip_color_codes(&tag).trim().to_lowercase()))
+        .filter(|key| !key.is_empty())
+}
+
+fn name_tag(id: u8) -> Option<String> {
+    rendering::display_names()
+        .into_iter()
+        .find_map(|(other, name)| (other == id).then_some(name))
+}
+
+/// Entity `name` (lowercased) would mute, if they're here.
+fn find_entity(name: &str) -> Option<u8> {
+    find_player_id_by_name(name).or_else(|| {
+        rendering::display_names()
+            .into_iter()
+            .find(|(id, tag)| {
+                get_player_name(*id).is_none()
+                    && mute_key(None, Some(tag.clone())).as_deref() == Some(name)
+            })
+            .map(|(id, _)| id)
+    })
+}
+
+/// Returns false if `name` was already ignored.
+pub fn add(name: &str) -> Result<bool> {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() {
+        return Ok(false);
+    }
+    let id = find_entity(&name);
+    if id == Some(ENTITY_SELF_ID) {
+        bail!("you can't mute yourself");
+    }
+    // Clear whatever they're showing now; once ignored, their events never
+    // reach the bubble.
+    if let Some(id) = id {
+        listener::clear(id);
+    }
+    let added = IGNORED.with_borrow_mut(|ignored| ignored.insert(name));
+    if added {
+        save();
+    }
+    Ok(added)
+}
+
+/// Returns false if `name` wasn't ignored.
+pub fn remove(name: &str) -> bool {
+    let removed = IGNORED.with_borrow_mut(|ignored| ignored.remove(&name.trim().to_lowercase()));
+    if removed {
+        save();
+    }
+    removed
+}
+
+/// Sorted, lowercased.
+pub fn names() -> Vec<String> {
+    IGNORED.with_borrow(|ignored| ignored.iter().cloned().collect())
+}
+
+pub fn is_ignored(id: u8) -> bool {
+    if id == ENTITY_SELF_ID || IGNORED.with_borrow(BTreeSet::is_empty) {
+        return false;
+    }
+    let account_name = get_player_name(id);
+    let name_tag = if account_name.is_none() {
+        name_tag(id)
+    } else {
+        None
+    };
+    mute_key(account_name, name_tag)
+        .is_some_and(|key| IGNORED.with_borrow(|ignored| ignored.contains(&key)))
+}
+
+pub fn initialize() {
+    let ignored = settings::read_option(OPTION_NAME)
+        .map(|value| parse(&value))
+        .unwrap_or_default();
+    debug!(?ignored, "loaded ignore list");
+    IGNORED.with_borrow_mut(|slot| *slot = ignored);
+}
+
+pub fn free() {
+    IGNORED.with_borrow_mut(BTreeSet::clear);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::{mute_key, parse, serialize};
+
+    #[test]
+    fn round_trips_through_option_value() {
+        let names = parse(" Goodly+, spammer ,,SPAMMER");
+        assert_eq!(
+            names,
+            BTreeSet::from(["goodly+".to_string(), "spammer".to_string()])
+        );
+        assert_eq!(serialize(&names), "goodly+,spammer");
+        assert_eq!(parse(&serialize(&names)), names);
+        assert!(parse("").is_empty());
+    }
+
+    #[test]
+    fn bots_are_muted_by_name_tag() {
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(mute_key(some("Goodly+"), some("&6Goodly")), some("goodly+"));
+        assert_eq!(mute_key(None, some("&eShop&fkeeper ")), some("shopkeeper"));
+        assert_eq!(mute_key(None, some("&f")), None);
+        assert_eq!(mute_key(None, None), None);
+    }
+}
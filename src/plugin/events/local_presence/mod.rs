@@ -4,29 +4,53 @@ pub mod wordwrap;
 #[cfg(test)]
 mod tests;
 
-use std::{cell::RefCell, ptr::NonNull};
+use std::{
+    cell::{Cell, RefCell},
+    ptr::NonNull,
+    time::{Duration, Instant},
+};
 
 use classicube_helpers::entities::ENTITY_SELF_ID;
 use classicube_sys::{
-    Drawer2D, Gui_GetInputGrab, Gui_GetScreen, GuiPriority_GUI_PRIORITY_CHAT,
+    Drawer2D, Entities, Gui_GetInputGrab, Gui_GetScreen, GuiPriority_GUI_PRIORITY_CHAT,
     GuiPriority_GUI_PRIORITY_INVENTORY, GuiPriority_GUI_PRIORITY_MENU,
-    GuiPriority_GUI_PRIORITY_TABLIST, PackedCol_A, Screen,
+    GuiPriority_GUI_PRIORITY_TABLIST, PackedCol_A, Screen, Window_Main,
 };
 
 use self::chat_screen::ChatScreen;
-use crate::plugin::events::{
-    chat_message::is_in_whisper_mode,
-    player_chat_event::{PlayerChatEvent, Presence},
+use crate::plugin::{
+    events::{
+        chat_message::is_in_whisper_mode,
+        player_chat_event::{PlayerChatEvent, Presence},
+    },
+    settings,
 };
 
 thread_local!(
     static LAST_PRESENCE: RefCell<Option<Presence>> = Default::default();
 );
 
+/// Last observed local-player state, and when it last changed.
+#[derive(Debug, PartialEq)]
+struct ActivitySample {
+    position: (f32, f32, f32),
+    yaw: f32,
+    pitch: f32,
+    input: Option<String>,
+}
+
+thread_local!(
+    static LAST_ACTIVITY: RefCell<Option<(ActivitySample, Instant)>> = Default::default();
+);
+
+thread_local!(
+    static AFK_SINCE: Cell<Option<Instant>> = Default::default();
+);
+
 pub fn poll() {
     let presence = compute_presence();
     let changed = LAST_PRESENCE.with_borrow_mut(|last| {
-        if last.as_ref() != presence.as_ref() {
+        if !same_presence(last.as_ref(), presence.as_ref()) {
             *last = presence.clone();
             true
         } else {
@@ -38,12 +62,77 @@ pub fn poll() {
     }
 }
 
+/// `Afk` carries an elapsed-seconds count that ticks every poll; only the
+/// transition into or out of it is a change worth emitting.
+fn same_presence(a: Option<&Presence>, b: Option<&Presence>) -> bool {
+    match (a, b) {
+        (Some(Presence::Afk(_)), Some(Presence::Afk(_))) => true,
+        _ => a == b,
+    }
+}
+
+/// Current `Presence::Afk` for the local player with an up-to-date elapsed
+/// count, or `None` if not AFK. Heartbeats and `WhosThere` replies use this
+/// so late joiners see how long we've actually been away.
+pub fn afk_presence() -> Option<Presence> {
+    AFK_SINCE
+        .get()
+        .map(|since| Presence::Afk(since.elapsed().as_secs().try_into().unwrap_or(u32::MAX)))
+}
+
+/// No movement, mouse-look or chat-input edits for `timeout` reports the
+/// local player as `Presence::Afk`. Losing window focus does so immediately.
+fn is_afk(idle_for: Duration, focused: bool, timeout: Duration) -> bool {
+    !focused || idle_for >= timeout
+}
+
+/// Returns how long the local player has gone without moving, looking around
+/// or editing the chat input.
+fn track_activity(input: Option<&str>, now: Instant) -> Duration {
+    let sample = unsafe {
+        let entity = Entities.List[ENTITY_SELF_ID as usize];
+        if entity.is_null() {
+            return Duration::ZERO;
+        }
+        let entity = &*entity;
+        ActivitySample {
+            position: (entity.Position.x, entity.Position.y, entity.Position.z),
+            yaw: entity.Yaw,
+            pitch: entity.Pitch,
+            input: input.map(str::to_string),
+        }
+    };
+    LAST_ACTIVITY.with_borrow_mut(|last| match last {
+        Some((last_sample, last_active)) if *last_sample == sample => {
+            now.saturating_duration_since(*last_active)
+        }
+        _ => {
+            *last = Some((sample, now));
+            Duration::ZERO
+        }
+    })
+}
+
 fn compute_presence() -> Option<Presence> {
+    let input = read_chat_input();
+    let now = Instant::now();
+    let idle_for = track_activity(input.as_deref(), now);
+    let focused = unsafe { Window_Main.Focused } != 0;
+
+    if is_afk(idle_for, focused, settings::get().afk_timeout) {
+        // Backdate to when input stopped so the label counts the whole idle
+        // stretch, not just the time since the timeout tripped.
+        let since = AFK_SINCE.get().unwrap_or_else(|| now - idle_for);
+        AFK_SINCE.set(Some(since));
+        return afk_presence();
+    }
+    AFK_SINCE.set(None);
+
     unsafe {
         if !Gui_GetScreen(GuiPriority_GUI_PRIORITY_MENU as _).is_null() {
             return Some(Presence::EscapeMenu);
         }
-        if let Some(text) = read_chat_input() {
+        if let Some(text) = input {
             return Some(Presence::Typing(text));
         }
         if !Gui_GetScreen(GuiPriority_GUI_PRIORITY_INVENTORY as _).is_null() {
@@ -127,6 +216,10 @@ fn format_input_line(
     String::from_utf8(out).expect("ascii-only byte swap preserves utf-8")
 }
 
+/// Shown instead of the typed text when it mustn't leak. Bubbles draw it as
+/// animated dots rather than literally.
+pub const TYPING_PLACEHOLDER: &str = "...";
+
 /// Maps the formatted chat-input line to what the typing bubble should show.
 /// Empty input hides the bubble; private commands / whispers / ops-messages
 /// and whisper-mode collapse to a `...` placeholder so the contents never leak
@@ -139,7 +232,7 @@ fn display_for_input(text: &str, whisper_mode: bool) -> String {
     if text.is_empty() {
         String::new()
     } else if is_sensitive_text(text) || whisper_mode {
-        "...".to_string()
+        TYPING_PLACEHOLDER.to_string()
     } else {
         text.to_string()
     }
@@ -166,4 +259,8 @@ pub fn free() {
     LAST_PRESENCE.with_borrow_mut(|option| {
         option.take();
     });
+    LAST_ACTIVITY.with_borrow_mut(|option| {
+        option.take();
+    });
+    AFK_SINCE.set(None);
 }
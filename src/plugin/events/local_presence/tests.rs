@@ -1,4 +1,7 @@
-use super::{display_for_input, format_input_line, is_sensitive_text};
+use std::time::Duration;
+
+use super::{display_for_input, format_input_line, is_afk, is_sensitive_text, same_presence};
+use crate::plugin::{events::player_chat_event::Presence, settings::Settings};
 
 /// Default ClassiCube palette covers '0'..='9', 'a'..='f', 'A'..='F'.
 fn default_palette(c: u8) -> bool {
@@ -126,3 +129,34 @@ fn is_sensitive_text_filters_whispers_and_commands() {
     assert!(is_sensitive_text("#"));
     assert!(is_sensitive_text("+"));
 }
+
+#[test]
+fn afk_after_timeout_or_focus_loss() {
+    let timeout = Settings::default().afk_timeout;
+    assert!(!is_afk(Duration::ZERO, true, timeout));
+    assert!(!is_afk(timeout - Duration::from_secs(1), true, timeout));
+    assert!(is_afk(timeout, true, timeout));
+    // Alt-tabbing away is AFK right away.
+    assert!(is_afk(Duration::ZERO, false, timeout));
+}
+
+#[test]
+fn afk_elapsed_ticks_are_not_changes() {
+    assert!(same_presence(
+        Some(&Presence::Afk(5)),
+        Some(&Presence::Afk(65))
+    ));
+    assert!(!same_presence(Some(&Presence::Afk(5)), None));
+    assert!(!same_presence(
+        Some(&Presence::Afk(5)),
+        Some(&Presence::EscapeMenu)
+    ));
+    assert!(same_presence(
+        Some(&Presence::Typing("a".to_string())),
+        Some(&Presence::Typing("a".to_string()))
+    ));
+    assert!(!same_presence(
+        Some(&Presence::Typing("a".to_string())),
+        Some(&Presence::Typing("b".to_string()))
+    ));
+}
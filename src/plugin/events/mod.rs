@@ -1,8 +1,13 @@
+pub mod bridged_chat;
 pub mod chat_message;
+pub mod history;
+pub mod ignore_list;
 pub mod local_presence;
 pub mod player_chat_event;
 
 pub fn initialize() {
+    ignore_list::initialize();
+    player_chat_event::initialize();
     chat_message::initialize();
 }
 
@@ -10,4 +15,7 @@ pub fn free() {
     player_chat_event::free();
     chat_message::free();
     local_presence::free();
+    history::free();
+    bridged_chat::free();
+    ignore_list::free();
 }
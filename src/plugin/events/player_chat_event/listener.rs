@@ -8,6 +8,9 @@ use super::PlayerChatEvent;
 
 pub trait PlayerChatEventListener {
     fn handle_event(&mut self, event: &PlayerChatEvent);
+
+    /// Forget everything shown for the player, e.g. when they get muted.
+    fn clear(&mut self) {}
 }
 
 pub trait StartStopListening {
@@ -25,6 +28,17 @@ pub fn with_all_listeners<R, F: FnOnce(&mut HashMap<u8, Vec<Inner>>) -> R>(f: F)
     EVENT_LISTENERS.with_borrow_mut(|listeners| f(listeners))
 }
 
+/// `PlayerChatEventListener::clear` on every listener of `entity_id`.
+pub fn clear(entity_id: u8) {
+    with_all_listeners(|map| {
+        for listener in map.get(&entity_id).into_iter().flatten() {
+            if let Some(listener) = listener.upgrade() {
+                listener.borrow_mut().clear();
+            }
+        }
+    })
+}
+
 pub fn free() {
     EVENT_LISTENERS.with_borrow_mut(|listeners| {
         listeners.clear();
@@ -4,12 +4,19 @@ use std::{
 };
 
 use classicube_helpers::async_manager;
-use classicube_relay::packet::MapScope;
+use classicube_relay::packet::{MapScope, PlayerScope};
 use futures::future::AbortHandle;
 use tracing::{debug, error};
 
 use super::{PlayerChatEvent, Presence};
-use crate::plugin::networking::message::RelayMessage;
+use crate::plugin::{
+    events::local_presence::{TYPING_PLACEHOLDER, afk_presence},
+    networking::{
+        message::{Capabilities, RelayMessage},
+        peers::{self, PeerInfo},
+    },
+    settings,
+};
 
 thread_local!(
     static DEBOUNCE_FUTURE: RefCell<Option<AbortHandle>> = Default::default();
@@ -23,11 +30,121 @@ thread_local!(
     static BROADCAST_SNAPSHOT: RefCell<Option<Presence>> = Default::default();
 );
 
+thread_local!(
+    static LAST_BROADCAST: Cell<Option<Instant>> = Default::default();
+);
+
+thread_local!(
+    static HEARTBEAT_FUTURE: RefCell<Option<AbortHandle>> = Default::default();
+);
+
+/// With `typing_previews` off, other players still get a typing indicator,
+/// but the text is replaced with the same `...` placeholder used for
+/// sensitive input.
+fn mask_typing(presence: Option<Presence>) -> Option<Presence> {
+    match presence {
+        Some(Presence::Typing(text)) if !settings::get().typing_previews && !text.is_empty() => {
+            Some(Presence::Typing(TYPING_PLACEHOLDER.to_string()))
+        }
+        other => other,
+    }
+}
+
 pub fn current_broadcast_snapshot() -> Option<Presence> {
-    BROADCAST_SNAPSHOT.with_borrow(|s| s.clone())
+    match BROADCAST_SNAPSHOT.with_borrow(|s| s.clone()) {
+        // The snapshot's elapsed count is from when we went AFK; refresh it.
+        Some(Presence::Afk(secs)) => afk_presence().or(Some(Presence::Afk(secs))),
+        other => other,
+    }
+}
+
+/// What a peer with `capabilities` is sent in place of `presence`.
+/// `Presence::Afk` is new in protocol version 2: older peers can't decode it
+/// and would keep showing our last status, so they get `None` instead.
+pub fn presence_for(presence: Option<Presence>, capabilities: Capabilities) -> Option<Presence> {
+    match presence {
+        Some(Presence::Afk(_)) if !capabilities.contains(Capabilities::AFK) => None,
+        other => other,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Audience {
+    Map,
+    Player(u8),
+}
+
+/// How to send `event` to a map with the known `peers` on it. Usually one
+/// broadcast; when version 0 players couldn't decode it, the map gets the form
+/// every version understands and each peer known to understand the real one
+/// gets it after. That holds even if every known peer is capable: a version 0
+/// player who hasn't sent us anything yet isn't in `peers` at all.
+pub(crate) fn plan_send(
+    event: PlayerChatEvent,
+    peers: &[(u8, PeerInfo)],
+) -> Vec<(Audience, PlayerChatEvent)> {
+    let PlayerChatEvent::PresenceChanged(presence) = &event else {
+        return vec![(Audience::Map, event)];
+    };
+    let understands =
+        |info: &PeerInfo| presence_for(presence.clone(), info.capabilities) == *presence;
+    let legacy = presence_for(presence.clone(), Capabilities::LEGACY);
+    if legacy == *presence {
+        return vec![(Audience::Map, event)];
+    }
+
+    let mut plan = vec![(Audience::Map, PlayerChatEvent::PresenceChanged(legacy))];
+    plan.extend(
+        peers
+            .iter()
+            .filter(|(_, info)| understands(info))
+            .map(|(id, _)| (Audience::Player(*id), event.clone())),
+    );
+    plan
 }
 
-const INTERVAL: Duration = Duration::from_millis(500);
+pub(crate) const INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often an unchanged presence is re-broadcast. Receivers expire a
+/// heartbeat-capable sender's status after `Settings::status_timeout` of
+/// silence, so that must stay comfortably above this.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn initialize() {
+    let (f, handle) = futures::future::abortable(async move {
+        loop {
+            async_manager::sleep(HEARTBEAT_INTERVAL).await;
+            heartbeat();
+        }
+    });
+    HEARTBEAT_FUTURE.with_borrow_mut(|option| {
+        if let Some(old) = option.replace(handle) {
+            old.abort();
+        }
+    });
+    async_manager::spawn_local_on_main_thread(async move {
+        let _ = f.await;
+    });
+}
+
+/// Re-send the current snapshot if nothing has gone out for a full interval,
+/// so receivers can tell "still typing" from "crashed mid-sentence". A pending
+/// typing debounce will send on its own shortly, so skip rather than race it
+/// with older text.
+fn heartbeat() {
+    let Some(presence) = current_broadcast_snapshot() else {
+        return;
+    };
+    let recently_sent = LAST_BROADCAST
+        .get()
+        .is_some_and(|last| last.elapsed() < HEARTBEAT_INTERVAL);
+    let debounce_pending = DEBOUNCE_FUTURE
+        .with_borrow(|future| future.as_ref().is_some_and(|handle| !handle.is_aborted()));
+    if recently_sent || debounce_pending {
+        return;
+    }
+    send(PlayerChatEvent::PresenceChanged(Some(presence)));
+}
 
 pub fn handle_local_emit(event: PlayerChatEvent) {
     DEBOUNCE_FUTURE.with_borrow_mut(move |debounce_future| match &event {
@@ -36,18 +153,13 @@ pub fn handle_local_emit(event: PlayerChatEvent) {
                 handle.abort();
             }
 
-            let instant = if let Some(last_send) = LAST_SEND.get() {
-                Instant::now().duration_since(last_send) > INTERVAL
-            } else {
-                true
-            };
-
-            if instant {
+            if !is_debounced(LAST_SEND.get(), Instant::now()) {
                 LAST_SEND.set(Some(Instant::now()));
                 send(event);
             } else {
                 let (f, handle) = futures::future::abortable(async move {
                     async_manager::sleep(INTERVAL).await;
+                    DEBOUNCE_FUTURE.with_borrow_mut(|option| option.take());
                     LAST_SEND.set(Some(Instant::now()));
                     send(event);
                 });
@@ -68,34 +180,62 @@ pub fn handle_local_emit(event: PlayerChatEvent) {
             send(event);
         }
 
-        PlayerChatEvent::Message(_) | PlayerChatEvent::MessageContinuation(_) => {
+        PlayerChatEvent::Message(_)
+        | PlayerChatEvent::MessageContinuation(_)
+        | PlayerChatEvent::AfkChanged(_)
+        | PlayerChatEvent::Action(_) => {
             // chat-received-derived events are never relayed; the receiving
             // side regenerates them from its own ChatReceivedEvent stream.
         }
     });
 }
 
+/// Typing updates within `INTERVAL` of the last send are held back and
+/// coalesced into one trailing send.
+pub(crate) fn is_debounced(last_send: Option<Instant>, now: Instant) -> bool {
+    last_send.is_some_and(|last_send| now.duration_since(last_send) <= INTERVAL)
+}
+
 #[tracing::instrument]
 fn send(event: PlayerChatEvent) {
     debug!("");
-    match &event {
+    let event = match event {
         PlayerChatEvent::PresenceChanged(presence) => {
+            let presence = mask_typing(presence);
             BROADCAST_SNAPSHOT.with_borrow_mut(|s| *s = presence.clone());
+            PlayerChatEvent::PresenceChanged(presence)
+        }
+        other @ (PlayerChatEvent::Message(_)
+        | PlayerChatEvent::MessageContinuation(_)
+        | PlayerChatEvent::AfkChanged(_)
+        | PlayerChatEvent::Action(_)) => other,
+    };
+    LAST_BROADCAST.set(Some(Instant::now()));
+    for (audience, event) in plan_send(event, &peers::get_all()) {
+        let message = RelayMessage::PlayerChatEvent(event);
+        let result = match audience {
+            Audience::Map => message.send(MapScope { have_plugin: true }),
+            Audience::Player(player_id) => message.send(PlayerScope { player_id }),
+        };
+        if let Err(e) = result {
+            error!("{:?}", e);
         }
-        PlayerChatEvent::Message(_) | PlayerChatEvent::MessageContinuation(_) => {}
-    }
-    if let Err(e) = RelayMessage::PlayerChatEvent(event).send(MapScope { have_plugin: true }) {
-        error!("{:?}", e);
     }
 }
 
 pub fn free() {
+    HEARTBEAT_FUTURE.with_borrow_mut(|option| {
+        if let Some(handle) = option.take() {
+            handle.abort()
+        }
+    });
     DEBOUNCE_FUTURE.with_borrow_mut(move |debounce_future| {
         if let Some(handle) = debounce_future.take() {
             handle.abort()
         }
     });
     LAST_SEND.set(None);
+    LAST_BROADCAST.set(None);
     BROADCAST_SNAPSHOT.with_borrow_mut(|s| {
         s.take();
     });
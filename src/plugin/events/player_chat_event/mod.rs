@@ -6,6 +6,7 @@ use serde::{Deserialize, Serialize};
 use tracing::debug;
 
 use self::listener::with_all_listeners;
+use super::{history, ignore_list};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Presence {
@@ -13,9 +14,13 @@ pub enum Presence {
     EscapeMenu,
     BlockMenu,
     TabList,
+    /// Idle or unfocused. Carries how many seconds the sender had been away
+    /// when this was sent, so receivers can show an elapsed-time label
+    /// without a shared clock.
+    Afk(u32),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PlayerChatEvent {
     PresenceChanged(Option<Presence>),
     Message(String),
@@ -26,12 +31,27 @@ pub enum PlayerChatEvent {
     /// same break points the server used instead of re-wrapping the join.
     /// Locally produced from `ChatReceivedEvent`; never sent over relay.
     MessageContinuation(Vec<String>),
+    /// Server-announced AFK toggle (MCGalaxy's "is now AFK" / "is no longer
+    /// AFK" lines), so players without the plugin still get an AFK icon.
+    /// Locally produced from `ChatReceivedEvent`; never sent over relay.
+    AfkChanged(bool),
+    /// A `/me` line (`*Name waves`), carrying what comes after the name.
+    /// Continuations of it arrive as `MessageContinuation` like any other
+    /// message. Locally produced from `ChatReceivedEvent`; never sent over
+    /// relay.
+    Action(String),
 }
 
 impl PlayerChatEvent {
     pub fn emit(self, entity_id: u8) {
         debug!(?entity_id, ?self, "emit");
 
+        if ignore_list::is_ignored(entity_id) {
+            debug!(?entity_id, "ignored, dropping");
+            return;
+        }
+        history::record(entity_id, &self);
+
         with_all_listeners(|map| {
             if let Some(listeners) = map.get_mut(&entity_id) {
                 listeners.retain(|listener| {
@@ -51,6 +71,10 @@ impl PlayerChatEvent {
     }
 }
 
+pub fn initialize() {
+    local_handler::initialize();
+}
+
 pub fn free() {
     local_handler::free();
     listener::free();
@@ -1,6 +1,8 @@
+pub mod command;
 pub mod events;
 pub mod networking;
 pub mod rendering;
+pub mod settings;
 
 use classicube_helpers::async_manager;
 use tracing::debug;
@@ -9,10 +11,12 @@ pub fn initialize() {
     debug!("plugin initialize");
 
     async_manager::initialize();
+    settings::initialize();
 
     rendering::initialize();
     events::initialize();
     networking::initialize();
+    command::initialize();
 }
 
 pub fn on_new_map() {
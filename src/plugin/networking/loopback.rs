@@ -0,0 +1,115 @@
+//! In-memory `Transport` for driving the relay path end to end in tests.
+//!
+//! There's only one "local" client per process (all the plugin state is
+//! thread-local), so the loopback models the rest of the map and the relay
+//! server between them. Every stream is packetized like on the real channel
+//! and each packet is raised as a plugin message, so a `RelayListener` does
+//! the decoding and reassembly:
+//!
+//! - `LoopbackNetwork::inject` plays a message from any other player id into
+//!   the plugin's own listener (`networking::listen`), the one
+//!   `networking::initialize` installs.
+//! - Anything the local client sends is reassembled by a listener on a side
+//!   channel and lands in `LoopbackNetwork::take_sent`.
+
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+use anyhow::Result;
+use classicube_helpers::entities::ENTITY_SELF_ID;
+use classicube_relay::{
+    RelayListener, Stream,
+    packet::{PlayerScope, Scope},
+};
+use classicube_sys::{Event_RaisePluginMessage, NetEvents};
+
+use super::{
+    message::{RELAY_CHANNEL, RelayMessage},
+    transport::{self, Transport},
+};
+
+/// Otherwise unused plugin-message channel our own sends are echoed on, so
+/// they don't reach the plugin's listener as if someone else sent them.
+const ECHO_CHANNEL: u8 = RELAY_CHANNEL + 1;
+
+type Outbox = Rc<RefCell<VecDeque<Vec<u8>>>>;
+
+/// What the relay server does with a stream from `sender`: the same data,
+/// packetized with the scope rewritten to say who sent it, delivered packet
+/// by packet to `channel`.
+fn forward(channel: u8, sender: u8, compressed_data: Vec<u8>) -> Result<()> {
+    let stream = Stream::new(
+        compressed_data,
+        Scope::from(PlayerScope { player_id: sender }),
+    )?;
+    for packet in stream.packets()? {
+        let mut data = packet.encode()?;
+        unsafe {
+            Event_RaisePluginMessage(
+                &raw mut NetEvents.PluginMessageReceived,
+                channel,
+                data.as_mut_ptr(),
+            );
+        }
+    }
+    Ok(())
+}
+
+struct LoopbackTransport;
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, _scope: Scope, compressed_data: Vec<u8>) -> Result<()> {
+        forward(ECHO_CHANNEL, ENTITY_SELF_ID, compressed_data)
+    }
+}
+
+/// Installed for as long as it's alive; dropping it uninstalls the transport
+/// and both listeners.
+pub struct LoopbackNetwork {
+    outbox: Outbox,
+    _listener: RelayListener,
+    _echo: RelayListener,
+}
+
+impl LoopbackNetwork {
+    pub fn install() -> Self {
+        let outbox = Outbox::default();
+        let mut echo = RelayListener::new(ECHO_CHANNEL).unwrap();
+        echo.on({
+            let outbox = outbox.clone();
+            move |_player_id, compressed_data| {
+                outbox.borrow_mut().push_back(compressed_data.to_vec());
+            }
+        });
+        transport::set_transport(Some(Box::new(LoopbackTransport)));
+        Self {
+            outbox,
+            _listener: super::listen().unwrap(),
+            _echo: echo,
+        }
+    }
+
+    /// Everything the local client has sent since the last call, decoded.
+    pub fn take_sent(&self) -> Vec<RelayMessage> {
+        self.outbox
+            .borrow_mut()
+            .drain(..)
+            .map(|compressed_data| {
+                let data = zstd::decode_all(&*compressed_data).unwrap();
+                RelayMessage::decode(&data).unwrap().1.unwrap()
+            })
+            .collect()
+    }
+
+    /// Deliver `message` as if `player_id` had sent it over the relay.
+    /// Errors from handling it are logged by the listener, as in the game.
+    pub fn inject(&self, player_id: u8, message: &RelayMessage) -> Result<()> {
+        let compressed_data = zstd::encode_all(&*message.encode()?, 0)?;
+        forward(RELAY_CHANNEL, player_id, compressed_data)
+    }
+}
+
+impl Drop for LoopbackNetwork {
+    fn drop(&mut self) {
+        transport::set_transport(None);
+    }
+}
@@ -1,17 +1,42 @@
+use std::time::{Duration, Instant};
+
 use anyhow::{Result, ensure};
-use classicube_helpers::entities::ENTITY_SELF_ID;
-use classicube_relay::{
-    Stream,
-    packet::{MapScope, Scope},
-};
+use classicube_helpers::{async_manager, entities::ENTITY_SELF_ID};
+use classicube_relay::packet::{PlayerScope, Scope};
 use classicube_sys::{INPUTWIDGET_LEN, INPUTWIDGET_MAX_LINES};
 use serde::{Deserialize, Serialize};
-use tracing::{error, trace, warn};
+use tracing::{debug, error, trace, warn};
 
+use super::{
+    peers::{self, PeerInfo},
+    rate_limit::{PresenceDecision, WhosThereDecision, with_rate_limiter},
+    transport,
+};
 use crate::plugin::events::player_chat_event::{PlayerChatEvent, Presence, local_handler};
 
 pub const RELAY_CHANNEL: u8 = 202;
 
+/// Bumped whenever `RelayMessage` (or anything it carries) gains a variant or
+/// changes shape. Peers on an older version skip payloads they can't decode
+/// instead of treating them as malformed.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Marks the end of a versioned message. The trailer rides *after* the
+/// bincode-legacy `RelayMessage` body rather than wrapping it, because
+/// `decode_from_slice` ignores trailing bytes: pre-trailer clients (version 0)
+/// still decode every variant they know about, so mixed-version maps keep
+/// working while plugin updates roll out.
+const TRAILER_MAGIC: [u8; 4] = *b"CBRv";
+
+/// `version: u16` + `capabilities: u32` (bincode legacy is fixed-width) plus
+/// the magic.
+const TRAILER_LEN: usize = 2 + 4 + TRAILER_MAGIC.len();
+
+/// Newest protocol version a trailer may claim. A v0 body can end in the
+/// magic by chance (or on purpose, in a `Typing` payload), so a trailer
+/// claiming a version this far ahead is read as part of the body instead.
+const MAX_TRAILER_VERSION: u16 = PROTOCOL_VERSION + 16;
+
 /// Cap on the UTF-8 byte length of a `Presence::Typing` payload from the
 /// relay. Local senders pull from a `ChatInputWidget` whose backing buffer
 /// is hard-capped at `INPUTWIDGET_MAX_LINES * INPUTWIDGET_LEN = 3 * 64 = 192`
@@ -23,6 +48,51 @@ pub const RELAY_CHANNEL: u8 = 202;
 const MAX_INPUT_TEXT_BYTES: usize =
     (INPUTWIDGET_MAX_LINES as usize) * (INPUTWIDGET_LEN as usize) * 3;
 
+/// Feature bits a peer advertises in every message's trailer, so receivers
+/// can tell which `RelayMessage` / `Presence` shapes a given player will
+/// understand before sending them something new.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// `Presence::{Typing, EscapeMenu, BlockMenu, TabList}`.
+    pub const PRESENCE: Self = Self(1 << 0);
+    /// Re-broadcasts an unchanged presence every
+    /// `local_handler::HEARTBEAT_INTERVAL`, so receivers may expire its
+    /// status after a silence. Never assumed for peers without it, or their
+    /// bubbles would blink out mid-sentence.
+    pub const HEARTBEAT: Self = Self(1 << 1);
+    /// `Presence::Afk`. Added in protocol version 2; `local_handler` sends
+    /// peers without it `None` instead.
+    pub const AFK: Self = Self(1 << 2);
+
+    /// Implied for version 0 peers, which never sent a trailer.
+    pub const LEGACY: Self = Self::PRESENCE;
+    /// Everything this build understands.
+    pub const LOCAL: Self = Self::PRESENCE.union(Self::HEARTBEAT).union(Self::AFK);
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Trailer {
+    version: u16,
+    capabilities: Capabilities,
+}
+
+/// Variants are only ever appended: bincode legacy encodes the variant index,
+/// so reordering or removing one breaks every other version on the map.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RelayMessage {
     WhosThere,
@@ -30,18 +100,61 @@ pub enum RelayMessage {
 }
 
 impl RelayMessage {
+    /// bincode-legacy body followed by this build's version/capability
+    /// trailer. Uncompressed; `send` runs it through zstd.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut data = bincode::serde::encode_to_vec(self, bincode::config::legacy())?;
+        let trailer = Trailer {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::LOCAL,
+        };
+        data.extend(bincode::serde::encode_to_vec(
+            &trailer,
+            bincode::config::legacy(),
+        )?);
+        data.extend(TRAILER_MAGIC);
+        Ok(data)
+    }
+
+    /// Splits off the trailer (if any) and decodes the body. Returns `None`
+    /// for the message when the sender is on a newer protocol and the body
+    /// didn't decode -- most likely a variant this build doesn't know yet,
+    /// which is expected on mixed-version maps and not worth an error.
+    ///
+    /// Bytes that only look like a trailer (an implausible version, or a
+    /// body that doesn't decode without them) are retried as a whole version
+    /// 0 message, so the returned `PeerInfo` only ever comes from a trailer
+    /// that checked out.
+    pub fn decode(data: &[u8]) -> Result<(PeerInfo, Option<Self>)> {
+        let legacy = PeerInfo {
+            version: 0,
+            capabilities: Capabilities::LEGACY,
+        };
+        let Some((body, info)) = split_trailer(data) else {
+            return Ok((legacy, Some(decode_body(data)?)));
+        };
+
+        match decode_body(body) {
+            Ok(message) => Ok((info, Some(message))),
+            Err(e) => match decode_body(data) {
+                Ok(message) => {
+                    debug!(?info, ?e, "trailer was part of a version 0 body");
+                    Ok((legacy, Some(message)))
+                }
+                Err(_) if info.version > PROTOCOL_VERSION => {
+                    debug!(?info, ?e, "skipping message from newer protocol");
+                    Ok((info, None))
+                }
+                Err(_) => Err(e),
+            },
+        }
+    }
+
     pub fn send<S: Into<Scope>>(&self, scope: S) -> Result<()> {
         trace!("send {:#?}", self);
-        let data = bincode::serde::encode_to_vec(self, bincode::config::legacy())?;
+        let data = self.encode()?;
         let compressed_data = zstd::encode_all(&*data, 0)?;
-        let stream = Stream::new(compressed_data, scope)?;
-        for packet in stream.packets()? {
-            let mut data = packet.encode()?;
-
-            unsafe {
-                classicube_sys::CPE_SendPluginMessage(RELAY_CHANNEL, data.as_mut_ptr());
-            }
-        }
+        transport::send(scope.into(), compressed_data)?;
 
         Ok(())
     }
@@ -51,17 +164,32 @@ impl RelayMessage {
         ensure!(player_id != ENTITY_SELF_ID, "got ENTITY_SELF_ID");
 
         let data = zstd::decode_all(compressed_data)?;
-        let (relay_message, _): (RelayMessage, _) =
-            bincode::serde::decode_from_slice(&data, bincode::config::legacy())?;
+        let (info, relay_message) = Self::decode(&data)?;
+        peers::record(player_id, info);
+        let Some(relay_message) = relay_message else {
+            return Ok(());
+        };
         trace!(?player_id, ?relay_message, "");
         match relay_message {
             RelayMessage::WhosThere => {
-                if let Some(presence) = local_handler::current_broadcast_snapshot() {
-                    let reply = RelayMessage::PlayerChatEvent(PlayerChatEvent::PresenceChanged(
-                        Some(presence),
-                    ));
-                    if let Err(e) = reply.send(MapScope { have_plugin: true }) {
-                        error!("WhosThere reply: {:?}", e);
+                match with_rate_limiter(|limiter| {
+                    limiter.admit_whos_there(player_id, Instant::now())
+                }) {
+                    WhosThereDecision::ReplyNow => reply_whos_there(player_id),
+                    WhosThereDecision::ReplyLater(delay) => {
+                        let generation = with_rate_limiter(|limiter| limiter.generation());
+                        async_manager::spawn_local_on_main_thread(async move {
+                            async_manager::sleep(delay).await;
+                            let askers = with_rate_limiter(|limiter| {
+                                limiter.take_askers(generation, Instant::now())
+                            });
+                            for asker in askers {
+                                reply_whos_there(asker);
+                            }
+                        });
+                    }
+                    WhosThereDecision::Ignore => {
+                        debug!(?player_id, "WhosThere throttled");
                     }
                 }
             }
@@ -78,7 +206,10 @@ impl RelayMessage {
                         );
                         return Ok(());
                     }
-                    PlayerChatEvent::Message(_) | PlayerChatEvent::MessageContinuation(_) => {
+                    PlayerChatEvent::Message(_)
+                    | PlayerChatEvent::MessageContinuation(_)
+                    | PlayerChatEvent::AfkChanged(_)
+                    | PlayerChatEvent::Action(_) => {
                         // local_handler never relays these — receivers regenerate
                         // them from their own ChatReceivedEvent stream. Anything
                         // arriving here is malformed or hostile; drop it before
@@ -92,10 +223,203 @@ impl RelayMessage {
                     }
                     _ => {}
                 }
-                event.emit(player_id);
+                match with_rate_limiter(|limiter| {
+                    limiter.admit_presence(player_id, event, Instant::now())
+                }) {
+                    PresenceDecision::Emit(event) => event.emit(player_id),
+                    PresenceDecision::Deferred(delay) => {
+                        debug!(?player_id, ?delay, "presence throttled");
+                        let generation = with_rate_limiter(|limiter| limiter.generation());
+                        schedule_presence_flush(player_id, generation, delay);
+                    }
+                    PresenceDecision::Coalesced => {}
+                }
             }
         }
 
         Ok(())
     }
 }
+
+fn decode_body(body: &[u8]) -> Result<RelayMessage> {
+    let (message, _) = bincode::serde::decode_from_slice(body, bincode::config::legacy())?;
+    Ok(message)
+}
+
+/// The body and what its trailer says, if `data` ends in one with a
+/// plausible version.
+fn split_trailer(data: &[u8]) -> Option<(&[u8], PeerInfo)> {
+    let body_len = data.len().checked_sub(TRAILER_LEN)?;
+    if !data.ends_with(&TRAILER_MAGIC) {
+        return None;
+    }
+    let (trailer, _): (Trailer, _) =
+        bincode::serde::decode_from_slice(&data[body_len..], bincode::config::legacy()).ok()?;
+    if !(1..=MAX_TRAILER_VERSION).contains(&trailer.version) {
+        return None;
+    }
+    Some((
+        &data[..body_len],
+        PeerInfo {
+            version: trailer.version,
+            capabilities: trailer.capabilities,
+        },
+    ))
+}
+
+fn reply_whos_there(asker: u8) {
+    // Always reply, even with no presence to report: the reply's trailer is
+    // how the asker learns our version/capabilities. `PresenceChanged(None)`
+    // is harmless for every version. Only the asker needs it, so a map full
+    // of players answering a join costs one message each instead of
+    // everyone hearing everyone's reply.
+    let capabilities = peers::get(asker).map_or(Capabilities::LEGACY, |info| info.capabilities);
+    let reply = RelayMessage::PlayerChatEvent(PlayerChatEvent::PresenceChanged(
+        local_handler::presence_for(local_handler::current_broadcast_snapshot(), capabilities),
+    ));
+    if let Err(e) = reply.send(PlayerScope { player_id: asker }) {
+        error!("WhosThere reply: {:?}", e);
+    }
+}
+
+/// Emit the newest throttled presence for `player_id` once its bucket has a
+/// token again, so the receiver still converges on the sender's final state.
+/// Dropped if the rate limiter was cleared (map change) since `generation`.
+fn schedule_presence_flush(player_id: u8, generation: u32, delay: Duration) {
+    async_manager::spawn_local_on_main_thread(async move {
+        async_manager::sleep(delay).await;
+        match with_rate_limiter(|limiter| {
+            limiter.take_pending(player_id, generation, Instant::now())
+        }) {
+            Ok(Some(event)) => event.emit(player_id),
+            Ok(None) => {}
+            Err(delay) => schedule_presence_flush(player_id, generation, delay),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Capabilities, PROTOCOL_VERSION, RelayMessage, TRAILER_LEN, TRAILER_MAGIC, Trailer,
+    };
+    use crate::plugin::events::player_chat_event::{PlayerChatEvent, Presence};
+
+    fn body_with_trailer(body: Vec<u8>, version: u16) -> Vec<u8> {
+        let mut data = body;
+        data.extend(
+            bincode::serde::encode_to_vec(
+                Trailer {
+                    version,
+                    capabilities: Capabilities::LOCAL,
+                },
+                bincode::config::legacy(),
+            )
+            .unwrap(),
+        );
+        data.extend(TRAILER_MAGIC);
+        data
+    }
+
+    #[test]
+    fn round_trips_with_trailer() {
+        let data = RelayMessage::PlayerChatEvent(PlayerChatEvent::PresenceChanged(Some(
+            Presence::Typing("hi".to_string()),
+        )))
+        .encode()
+        .unwrap();
+        assert!(data.ends_with(&TRAILER_MAGIC));
+
+        let (info, message) = RelayMessage::decode(&data).unwrap();
+        assert_eq!(info.version, PROTOCOL_VERSION);
+        assert_eq!(info.capabilities, Capabilities::LOCAL);
+        assert!(matches!(
+            message,
+            Some(RelayMessage::PlayerChatEvent(PlayerChatEvent::PresenceChanged(Some(
+                Presence::Typing(text)
+            )))) if text == "hi"
+        ));
+    }
+
+    #[test]
+    fn trailer_is_fixed_width() {
+        let body = RelayMessage::WhosThere.encode().unwrap();
+        let bare =
+            bincode::serde::encode_to_vec(RelayMessage::WhosThere, bincode::config::legacy())
+                .unwrap();
+        assert_eq!(body.len(), bare.len() + TRAILER_LEN);
+    }
+
+    #[test]
+    fn legacy_decoder_ignores_trailer() {
+        // What a version 0 client does with our bytes: decode the body and
+        // ignore whatever follows.
+        let data = RelayMessage::WhosThere.encode().unwrap();
+        let (message, _): (RelayMessage, _) =
+            bincode::serde::decode_from_slice(&data, bincode::config::legacy()).unwrap();
+        assert!(matches!(message, RelayMessage::WhosThere));
+    }
+
+    #[test]
+    fn decodes_legacy_message_without_trailer() {
+        let data =
+            bincode::serde::encode_to_vec(RelayMessage::WhosThere, bincode::config::legacy())
+                .unwrap();
+        let (info, message) = RelayMessage::decode(&data).unwrap();
+        assert_eq!(info.version, 0);
+        assert_eq!(info.capabilities, Capabilities::LEGACY);
+        assert!(matches!(message, Some(RelayMessage::WhosThere)));
+    }
+
+    #[test]
+    fn legacy_body_ending_in_the_magic_is_not_a_trailer() {
+        // Garbage version, and a plausible one whose body is then cut short.
+        for text in ["typing CBRv", "xx\u{2}\0\u{7}\0\0\0CBRv"] {
+            let data = bincode::serde::encode_to_vec(
+                RelayMessage::PlayerChatEvent(PlayerChatEvent::PresenceChanged(Some(
+                    Presence::Typing(text.to_string()),
+                ))),
+                bincode::config::legacy(),
+            )
+            .unwrap();
+            assert!(data.ends_with(&TRAILER_MAGIC));
+
+            let (info, message) = RelayMessage::decode(&data).unwrap();
+            assert_eq!(info.version, 0);
+            assert_eq!(info.capabilities, Capabilities::LEGACY);
+            assert!(matches!(
+                message,
+                Some(RelayMessage::PlayerChatEvent(PlayerChatEvent::PresenceChanged(Some(
+                    Presence::Typing(decoded)
+                )))) if decoded == text
+            ));
+        }
+    }
+
+    #[test]
+    fn skips_unknown_variant_from_newer_peer() {
+        let data = body_with_trailer(99u32.to_le_bytes().to_vec(), PROTOCOL_VERSION + 1);
+        let (info, message) = RelayMessage::decode(&data).unwrap();
+        assert_eq!(info.version, PROTOCOL_VERSION + 1);
+        assert!(message.is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_variant_from_same_version() {
+        let data = body_with_trailer(99u32.to_le_bytes().to_vec(), PROTOCOL_VERSION);
+        assert!(RelayMessage::decode(&data).is_err());
+    }
+
+    #[test]
+    fn capabilities_contains() {
+        assert!(Capabilities::LOCAL.contains(Capabilities::PRESENCE));
+        assert!(Capabilities::LOCAL.contains(Capabilities::HEARTBEAT));
+        assert!(!Capabilities::LEGACY.contains(Capabilities::HEARTBEAT));
+        assert!(Capabilities::LOCAL.contains(Capabilities::NONE));
+        assert!(!Capabilities::NONE.contains(Capabilities::PRESENCE));
+        assert_eq!(
+            Capabilities::NONE.union(Capabilities::PRESENCE),
+            Capabilities::PRESENCE
+        );
+    }
+}
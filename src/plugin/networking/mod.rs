@@ -1,21 +1,35 @@
+#[cfg(test)]
+pub mod loopback;
 pub mod message;
+pub mod peers;
+pub mod rate_limit;
+pub mod transport;
+
+#[cfg(test)]
+mod tests;
 
 use std::cell::RefCell;
 
-use anyhow::Error;
+use anyhow::{Error, Result};
 use classicube_helpers::async_manager;
 use classicube_relay::{RelayListener, packet::MapScope};
 use tracing::{error, trace};
 
-use self::message::RELAY_CHANNEL;
+use self::{
+    message::RELAY_CHANNEL,
+    transport::{RelayTransport, set_transport},
+};
 use crate::plugin::networking::message::RelayMessage;
 
 thread_local!(
     static RELAY_LISTENER: RefCell<Option<RelayListener>> = Default::default();
 );
 
-pub fn initialize() {
-    let mut relay_listener = RelayListener::new(RELAY_CHANNEL).unwrap();
+/// Reassembles relay packets from the plugin-message channel and hands each
+/// complete stream to `RelayMessage::handle_receive`. The test loopback
+/// delivers into one of these too, so both go through the same path.
+pub fn listen() -> Result<RelayListener> {
+    let mut relay_listener = RelayListener::new(RELAY_CHANNEL)?;
     relay_listener.on(|player_id, compressed_data| {
         trace!(player_id, ?compressed_data, "relay data");
 
@@ -23,6 +37,13 @@ pub fn initialize() {
             error!("handle_receive: {:#?}", e);
         }
     });
+    Ok(relay_listener)
+}
+
+pub fn initialize() {
+    set_transport(Some(Box::new(RelayTransport)));
+
+    let relay_listener = listen().unwrap();
 
     RELAY_LISTENER.with_borrow_mut(move |option| {
         *option = Some(relay_listener);
@@ -30,6 +51,10 @@ pub fn initialize() {
 }
 
 pub fn on_new_map_loaded() {
+    // Everyone on the new map re-announces in their WhosThere replies.
+    peers::clear();
+    rate_limit::clear();
+
     async_manager::spawn_local_on_main_thread(async move {
         if let Err(e) = async move {
             // send request to everyone in map (to tell server we have this plugin)
@@ -47,4 +72,7 @@ pub fn free() {
     RELAY_LISTENER.with_borrow_mut(|option| {
         drop(option.take());
     });
+    set_transport(None);
+    peers::clear();
+    rate_limit::clear();
 }
@@ -0,0 +1,52 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use tracing::debug;
+
+use super::message::Capabilities;
+
+/// What we've learned about a remote player's plugin from the trailer on the
+/// last relay message they sent us. Peers that predate the versioned trailer
+/// show up as version 0 with `Capabilities::LEGACY`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+
+thread_local!(
+    static PEERS: RefCell<HashMap<u8, PeerInfo>> = Default::default();
+);
+
+pub fn record(player_id: u8, info: PeerInfo) {
+    PEERS.with_borrow_mut(|peers| {
+        if peers.insert(player_id, info) != Some(info) {
+            debug!(?player_id, ?info, "peer updated");
+        }
+    });
+}
+
+pub fn get(player_id: u8) -> Option<PeerInfo> {
+    PEERS.with_borrow(|peers| peers.get(&player_id).copied())
+}
+
+/// Snapshot of every known peer, sorted by player id.
+pub fn get_all() -> Vec<(u8, PeerInfo)> {
+    let mut all = PEERS.with_borrow(|peers| {
+        peers
+            .iter()
+            .map(|(id, info)| (*id, *info))
+            .collect::<Vec<_>>()
+    });
+    all.sort_by_key(|(id, _)| *id);
+    all
+}
+
+pub fn forget(player_id: u8) {
+    PEERS.with_borrow_mut(|peers| {
+        peers.remove(&player_id);
+    });
+}
+
+pub fn clear() {
+    PEERS.with_borrow_mut(|peers| peers.clear());
+}
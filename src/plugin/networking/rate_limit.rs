@@ -0,0 +1,407 @@
+//! Per-sender throttling for relay input. Every `PresenceChanged` re-bakes a
+//! bubble texture and every `WhosThere` makes us send a reply, so a hostile
+//! peer spamming either would otherwise cost every client on the map a
+//! texture upload / reply per packet.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::plugin::events::player_chat_event::PlayerChatEvent;
+
+/// Presence updates a sender may burst before being throttled. A well-behaved
+/// client sends at most one typing update per `local_handler::INTERVAL`, plus
+/// the occasional menu transition, so this only bites on abuse.
+const PRESENCE_BURST: f32 = 8.0;
+const PRESENCE_REFILL_PER_SEC: f32 = 4.0;
+
+/// Well-behaved clients send one `WhosThere` per map load.
+const WHOS_THERE_SENDER_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// Minimum spacing between our own `WhosThere` replies regardless of who
+/// asked, so a map full of joining players (or one sender cycling ids) can't
+/// make every client reply at once. The reply is just our current snapshot,
+/// so answering several askers together after the delay loses nothing.
+const WHOS_THERE_REPLY_COOLDOWN: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f32,
+    refill_per_sec: f32,
+    tokens: f32,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: f32, refill_per_sec: f32, now: Instant) -> Self {
+        Self {
+            capacity,
+            refill_per_sec,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last = now;
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until `try_take` would succeed.
+    pub fn time_until_token(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f32((1.0 - self.tokens) / self.refill_per_sec)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PresenceDecision {
+    /// Under the limit; emit immediately.
+    Emit(PlayerChatEvent),
+    /// Over the limit; the event replaced any earlier pending one and a flush
+    /// should be scheduled after the given delay.
+    Deferred(Duration),
+    /// Over the limit and a flush is already scheduled; the event replaced
+    /// the pending one and will go out with it.
+    Coalesced,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WhosThereDecision {
+    ReplyNow,
+    /// Schedule a reply after the given delay; it goes to everyone
+    /// `take_askers` returns by then.
+    ReplyLater(Duration),
+    /// Throttled, or riding along with an already-scheduled reply.
+    Ignore,
+}
+
+struct SenderState {
+    presence: TokenBucket,
+    /// Latest throttled presence event. Only the newest matters: presence is
+    /// a state, not a log, so intermediate updates can be dropped.
+    pending: Option<PlayerChatEvent>,
+    flush_scheduled: bool,
+    last_whos_there: Option<Instant>,
+}
+
+impl SenderState {
+    fn new(now: Instant) -> Self {
+        Self {
+            presence: TokenBucket::new(PRESENCE_BURST, PRESENCE_REFILL_PER_SEC, now),
+            pending: None,
+            flush_scheduled: false,
+            last_whos_there: None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    senders: HashMap<u8, SenderState>,
+    last_reply: Option<Instant>,
+    /// Askers waiting on the scheduled reply; empty when none is scheduled.
+    askers: Vec<u8>,
+    /// Bumped by `clear`, so tasks scheduled on the previous map find out
+    /// they're stale instead of sending its state into the new one.
+    generation: u32,
+}
+
+impl RateLimiter {
+    fn sender(&mut self, player_id: u8, now: Instant) -> &mut SenderState {
+        self.senders
+            .entry(player_id)
+            .or_insert_with(|| SenderState::new(now))
+    }
+
+    pub fn admit_presence(
+        &mut self,
+        player_id: u8,
+        event: PlayerChatEvent,
+        now: Instant,
+    ) -> PresenceDecision {
+        let sender = self.sender(player_id, now);
+        if !sender.flush_scheduled && sender.presence.try_take(now) {
+            return PresenceDecision::Emit(event);
+        }
+
+        sender.pending = Some(event);
+        if sender.flush_scheduled {
+            PresenceDecision::Coalesced
+        } else {
+            sender.flush_scheduled = true;
+            PresenceDecision::Deferred(sender.presence.time_until_token(now))
+        }
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Called when a flush scheduled during `generation` fires. `Ok(None)` if
+    /// the pending event was already cleared (e.g. map change); `Err(delay)`
+    /// if the bucket still hasn't refilled and the flush should be
+    /// rescheduled.
+    pub fn take_pending(
+        &mut self,
+        player_id: u8,
+        generation: u32,
+        now: Instant,
+    ) -> Result<Option<PlayerChatEvent>, Duration> {
+        if generation != self.generation {
+            return Ok(None);
+        }
+        let Some(sender) = self.senders.get_mut(&player_id) else {
+            return Ok(None);
+        };
+        if sender.pending.is_none() {
+            sender.flush_scheduled = false;
+            return Ok(None);
+        }
+        if !sender.presence.try_take(now) {
+            return Err(sender.presence.time_until_token(now));
+        }
+        sender.flush_scheduled = false;
+        Ok(sender.pending.take())
+    }
+
+    pub fn admit_whos_there(&mut self, player_id: u8, now: Instant) -> WhosThereDecision {
+        let sender = self.sender(player_id, now);
+        if sender
+            .last_whos_there
+            .is_some_and(|last| now.saturating_duration_since(last) < WHOS_THERE_SENDER_COOLDOWN)
+        {
+            return WhosThereDecision::Ignore;
+        }
+        sender.last_whos_there = Some(now);
+
+        if !self.askers.is_empty() {
+            if !self.askers.contains(&player_id) {
+                self.askers.push(player_id);
+            }
+            return WhosThereDecision::Ignore;
+        }
+        match self.last_reply {
+            Some(last) if now.saturating_duration_since(last) < WHOS_THERE_REPLY_COOLDOWN => {
+                self.askers.push(player_id);
+                WhosThereDecision::ReplyLater(
+                    WHOS_THERE_REPLY_COOLDOWN - now.saturating_duration_since(last),
+                )
+            }
+            _ => {
+                self.last_reply = Some(now);
+                WhosThereDecision::ReplyNow
+            }
+        }
+    }
+
+    /// Called when a `ReplyLater` scheduled during `generation` fires;
+    /// everyone to send the reply to.
+    pub fn take_askers(&mut self, generation: u32, now: Instant) -> Vec<u8> {
+        if generation != self.generation {
+            return Vec::new();
+        }
+        self.last_reply = Some(now);
+        std::mem::take(&mut self.askers)
+    }
+
+    pub fn forget(&mut self, player_id: u8) {
+        self.senders.remove(&player_id);
+    }
+
+    /// Forgets everything and invalidates scheduled replies and flushes.
+    pub fn clear(&mut self) {
+        *self = Self {
+            generation: self.generation.wrapping_add(1),
+            ..Default::default()
+        };
+    }
+}
+
+thread_local!(
+    static RATE_LIMITER: RefCell<RateLimiter> = Default::default();
+);
+
+pub fn with_rate_limiter<R, F: FnOnce(&mut RateLimiter) -> R>(f: F) -> R {
+    RATE_LIMITER.with_borrow_mut(f)
+}
+
+pub fn forget(player_id: u8) {
+    with_rate_limiter(|limiter| limiter.forget(player_id));
+}
+
+pub fn clear() {
+    with_rate_limiter(RateLimiter::clear);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        PRESENCE_BURST, PresenceDecision, RateLimiter, TokenBucket, WHOS_THERE_REPLY_COOLDOWN,
+        WHOS_THERE_SENDER_COOLDOWN, WhosThereDecision,
+    };
+    use crate::plugin::events::player_chat_event::{PlayerChatEvent, Presence};
+
+    fn typing(text: &str) -> PlayerChatEvent {
+        PlayerChatEvent::PresenceChanged(Some(Presence::Typing(text.to_string())))
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 1.0, now);
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+        assert_eq!(bucket.time_until_token(now), Duration::from_secs(1));
+        assert!(bucket.try_take(now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn bucket_caps_at_capacity() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(1.0, 10.0, now);
+        let later = now + Duration::from_secs(60);
+        assert!(bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+    }
+
+    #[test]
+    fn presence_burst_then_coalesces() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::default();
+        for i in 0..PRESENCE_BURST as usize {
+            assert_eq!(
+                limiter.admit_presence(1, typing(&i.to_string()), now),
+                PresenceDecision::Emit(typing(&i.to_string()))
+            );
+        }
+        assert!(matches!(
+            limiter.admit_presence(1, typing("a"), now),
+            PresenceDecision::Deferred(_)
+        ));
+        assert_eq!(
+            limiter.admit_presence(1, typing("b"), now),
+            PresenceDecision::Coalesced
+        );
+
+        // Only the newest pending update survives the flush.
+        assert!(limiter.take_pending(1, 0, now).is_err());
+        assert_eq!(
+            limiter.take_pending(1, 0, now + Duration::from_secs(1)),
+            Ok(Some(typing("b")))
+        );
+        assert_eq!(
+            limiter.take_pending(1, 0, now + Duration::from_secs(1)),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn presence_limits_are_per_sender() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::default();
+        for _ in 0..PRESENCE_BURST as usize {
+            limiter.admit_presence(1, typing("x"), now);
+        }
+        assert_eq!(
+            limiter.admit_presence(2, typing("y"), now),
+            PresenceDecision::Emit(typing("y"))
+        );
+    }
+
+    #[test]
+    fn whos_there_sender_cooldown() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::default();
+        assert_eq!(
+            limiter.admit_whos_there(1, now),
+            WhosThereDecision::ReplyNow
+        );
+        let soon = now + WHOS_THERE_REPLY_COOLDOWN * 2;
+        assert_eq!(limiter.admit_whos_there(1, soon), WhosThereDecision::Ignore);
+        let later = now + WHOS_THERE_SENDER_COOLDOWN;
+        assert_eq!(
+            limiter.admit_whos_there(1, later),
+            WhosThereDecision::ReplyNow
+        );
+    }
+
+    #[test]
+    fn whos_there_replies_are_shared_across_senders() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::default();
+        assert_eq!(
+            limiter.admit_whos_there(1, now),
+            WhosThereDecision::ReplyNow
+        );
+        assert_eq!(
+            limiter.admit_whos_there(2, now),
+            WhosThereDecision::ReplyLater(WHOS_THERE_REPLY_COOLDOWN)
+        );
+        // Third asker rides along with the already-scheduled reply.
+        assert_eq!(limiter.admit_whos_there(3, now), WhosThereDecision::Ignore);
+
+        let later = now + WHOS_THERE_REPLY_COOLDOWN;
+        assert_eq!(limiter.take_askers(0, later), [2, 3]);
+        assert!(limiter.take_askers(0, later).is_empty());
+        assert_eq!(
+            limiter.admit_whos_there(4, later + WHOS_THERE_REPLY_COOLDOWN),
+            WhosThereDecision::ReplyNow
+        );
+    }
+
+    #[test]
+    fn clear_invalidates_scheduled_work() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::default();
+        for _ in 0..PRESENCE_BURST as usize {
+            limiter.admit_presence(1, typing("x"), now);
+        }
+        assert!(matches!(
+            limiter.admit_presence(1, typing("old map"), now),
+            PresenceDecision::Deferred(_)
+        ));
+        limiter.admit_whos_there(2, now);
+        assert!(matches!(
+            limiter.admit_whos_there(3, now),
+            WhosThereDecision::ReplyLater(_)
+        ));
+        let generation = limiter.generation();
+
+        limiter.clear();
+        assert_ne!(limiter.generation(), generation);
+        // The same player throttled again on the new map.
+        for _ in 0..PRESENCE_BURST as usize {
+            limiter.admit_presence(1, typing("x"), now);
+        }
+        limiter.admit_presence(1, typing("new map"), now);
+
+        let later = now + Duration::from_secs(10);
+        assert_eq!(limiter.take_pending(1, generation, later), Ok(None));
+        assert!(limiter.take_askers(generation, later).is_empty());
+        assert_eq!(
+            limiter.take_pending(1, limiter.generation(), later),
+            Ok(Some(typing("new map")))
+        );
+    }
+}
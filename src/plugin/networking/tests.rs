@@ -0,0 +1,276 @@
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use classicube_helpers::entities::ENTITY_SELF_ID;
+
+use super::{
+    loopback::LoopbackNetwork,
+    message::{Capabilities, PROTOCOL_VERSION, RelayMessage},
+    peers::{self, PeerInfo},
+};
+use crate::plugin::{
+    events::player_chat_event::{
+        PlayerChatEvent, Presence,
+        listener::{PlayerChatEventListener, StartStopListening},
+        local_handler::{self, Audience, INTERVAL, is_debounced, plan_send, presence_for},
+    },
+    settings,
+};
+
+#[derive(Default)]
+struct Recorder {
+    events: Vec<PlayerChatEvent>,
+}
+
+impl PlayerChatEventListener for Recorder {
+    fn handle_event(&mut self, event: &PlayerChatEvent) {
+        self.events.push(event.clone());
+    }
+}
+
+fn typing(text: &str) -> PlayerChatEvent {
+    PlayerChatEvent::PresenceChanged(Some(Presence::Typing(text.to_string())))
+}
+
+#[test]
+fn whos_there_gets_a_reply_with_snapshot() {
+    let network = LoopbackNetwork::install();
+
+    network.inject(5, &RelayMessage::WhosThere).unwrap();
+
+    // No local presence yet, so the reply is an explicit `None`.
+    let sent = network.take_sent();
+    assert!(matches!(
+        sent.as_slice(),
+        [RelayMessage::PlayerChatEvent(
+            PlayerChatEvent::PresenceChanged(None)
+        )]
+    ));
+
+    let info = peers::get(5).unwrap();
+    assert_eq!(info.version, PROTOCOL_VERSION);
+    assert_eq!(info.capabilities, Capabilities::LOCAL);
+}
+
+#[test]
+fn remote_presence_reaches_listener() {
+    let network = LoopbackNetwork::install();
+    let recorder = Rc::new(RefCell::new(Recorder::default()));
+    recorder.start_listening(7);
+
+    network
+        .inject(7, &RelayMessage::PlayerChatEvent(typing("hello")))
+        .unwrap();
+    // Someone else's presence doesn't go to player 7's listeners.
+    network
+        .inject(8, &RelayMessage::PlayerChatEvent(typing("other")))
+        .unwrap();
+
+    assert_eq!(recorder.borrow().events, vec![typing("hello")]);
+    // Remote presence is never re-broadcast.
+    assert!(network.take_sent().is_empty());
+
+    recorder.stop_listening();
+}
+
+#[test]
+fn relayed_chat_is_dropped() {
+    let network = LoopbackNetwork::install();
+    let recorder = Rc::new(RefCell::new(Recorder::default()));
+    recorder.start_listening(7);
+
+    network
+        .inject(
+            7,
+            &RelayMessage::PlayerChatEvent(PlayerChatEvent::Message("spoofed".to_string())),
+        )
+        .unwrap();
+
+    assert!(recorder.borrow().events.is_empty());
+
+    recorder.stop_listening();
+}
+
+#[test]
+fn self_id_is_rejected() {
+    let network = LoopbackNetwork::install();
+    network
+        .inject(ENTITY_SELF_ID, &RelayMessage::WhosThere)
+        .unwrap();
+    assert!(network.take_sent().is_empty());
+    assert!(peers::get(ENTITY_SELF_ID).is_none());
+}
+
+/// Text zstd can't squeeze into one relay packet.
+fn incompressible_text(len: usize) -> String {
+    const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+    let mut state = 0x2545_f491_u32;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            CHARS[state as usize % CHARS.len()] as char
+        })
+        .collect()
+}
+
+#[test]
+fn multi_packet_typing_is_reassembled() {
+    let network = LoopbackNetwork::install();
+    let recorder = Rc::new(RefCell::new(Recorder::default()));
+    recorder.start_listening(7);
+    let text = incompressible_text(500);
+
+    network
+        .inject(7, &RelayMessage::PlayerChatEvent(typing(&text)))
+        .unwrap();
+    assert_eq!(recorder.borrow().events, vec![typing(&text)]);
+
+    typing(&text).emit(ENTITY_SELF_ID);
+    let sent = network.take_sent();
+    assert!(matches!(
+        sent.as_slice(),
+        [RelayMessage::PlayerChatEvent(event)] if *event == typing(&text)
+    ));
+
+    recorder.stop_listening();
+    local_handler::free();
+}
+
+#[test]
+fn local_typing_is_broadcast_and_answers_whos_there() {
+    let network = LoopbackNetwork::install();
+
+    typing("hi").emit(ENTITY_SELF_ID);
+    let sent = network.take_sent();
+    assert!(matches!(
+        sent.as_slice(),
+        [RelayMessage::PlayerChatEvent(event)] if *event == typing("hi")
+    ));
+
+    // A later asker gets the current snapshot back.
+    network.inject(9, &RelayMessage::WhosThere).unwrap();
+    let sent = network.take_sent();
+    assert!(matches!(
+        sent.as_slice(),
+        [RelayMessage::PlayerChatEvent(event)] if *event == typing("hi")
+    ));
+
+    local_handler::free();
+}
+
+#[test]
+fn discrete_presence_sends_immediately() {
+    let network = LoopbackNetwork::install();
+
+    typing("hi").emit(ENTITY_SELF_ID);
+    PlayerChatEvent::PresenceChanged(Some(Presence::EscapeMenu)).emit(ENTITY_SELF_ID);
+    PlayerChatEvent::PresenceChanged(None).emit(ENTITY_SELF_ID);
+
+    assert_eq!(network.take_sent().len(), 3);
+    assert_eq!(local_handler::current_broadcast_snapshot(), None);
+
+    local_handler::free();
+}
+
+#[test]
+fn typing_debounce_window() {
+    let now = Instant::now();
+    assert!(!is_debounced(None, now));
+    assert!(is_debounced(Some(now), now));
+    assert!(is_debounced(Some(now), now + INTERVAL));
+    assert!(!is_debounced(
+        Some(now),
+        now + INTERVAL + Duration::from_millis(1)
+    ));
+}
+
+#[test]
+fn typing_previews_off_masks_broadcast_text() {
+    let network = LoopbackNetwork::install();
+    settings::update(|s| s.typing_previews = false);
+
+    typing("secret plans").emit(ENTITY_SELF_ID);
+    let sent = network.take_sent();
+    assert!(matches!(
+        sent.as_slice(),
+        [RelayMessage::PlayerChatEvent(event)] if *event == typing("...")
+    ));
+    assert_eq!(
+        local_handler::current_broadcast_snapshot(),
+        Some(Presence::Typing("...".to_string()))
+    );
+
+    settings::update(|s| s.typing_previews = true);
+    local_handler::free();
+}
+
+#[test]
+fn afk_falls_back_to_none_for_legacy_peers() {
+    let afk = || PlayerChatEvent::PresenceChanged(Some(Presence::Afk(30)));
+    let current = PeerInfo {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::LOCAL,
+    };
+    let legacy = PeerInfo {
+        version: 0,
+        capabilities: Capabilities::LEGACY,
+    };
+
+    // Unknown players may be version 0, so the map never hears it directly,
+    // even when every known peer would understand.
+    assert_eq!(
+        plan_send(afk(), &[]),
+        [(Audience::Map, PlayerChatEvent::PresenceChanged(None))]
+    );
+    assert_eq!(
+        plan_send(afk(), &[(5, current)]),
+        [
+            (Audience::Map, PlayerChatEvent::PresenceChanged(None)),
+            (Audience::Player(5), afk()),
+        ]
+    );
+    // Mixed-version map: the map hears `None`, then each capable peer the
+    // real thing.
+    assert_eq!(
+        plan_send(afk(), &[(5, current), (6, legacy), (7, current)]),
+        [
+            (Audience::Map, PlayerChatEvent::PresenceChanged(None)),
+            (Audience::Player(5), afk()),
+            (Audience::Player(7), afk()),
+        ]
+    );
+    // Other presences go out as-is.
+    assert_eq!(
+        plan_send(typing("hi"), &[(6, legacy)]),
+        [(Audience::Map, typing("hi"))]
+    );
+    assert_eq!(
+        plan_send(typing("hi"), &[]),
+        [(Audience::Map, typing("hi"))]
+    );
+    assert_eq!(
+        presence_for(Some(Presence::Afk(30)), legacy.capabilities),
+        None
+    );
+
+    let network = LoopbackNetwork::install();
+    peers::record(5, current);
+    peers::record(6, legacy);
+    afk().emit(ENTITY_SELF_ID);
+    let sent = network.take_sent();
+    assert!(matches!(
+        sent.as_slice(),
+        [
+            RelayMessage::PlayerChatEvent(PlayerChatEvent::PresenceChanged(None)),
+            RelayMessage::PlayerChatEvent(event),
+        ] if *event == afk()
+    ));
+
+    peers::clear();
+    local_handler::free();
+}
@@ -0,0 +1,51 @@
+use std::cell::RefCell;
+
+use anyhow::{Result, bail};
+use classicube_relay::{Stream, packet::Scope};
+
+use super::message::RELAY_CHANNEL;
+
+/// Where encoded relay streams go. `RelayMessage::send` hands each compressed
+/// message to whichever transport is installed, so tests can swap the
+/// ClassiCube plugin-message channel for an in-memory loopback.
+pub trait Transport {
+    fn send(&mut self, scope: Scope, compressed_data: Vec<u8>) -> Result<()>;
+}
+
+/// Packetizes with `classicube_relay` and writes each packet to the server
+/// over `CPE_SendPluginMessage`. The receiving half is the `RelayListener`
+/// registered in `networking::initialize`.
+pub struct RelayTransport;
+
+impl Transport for RelayTransport {
+    fn send(&mut self, scope: Scope, compressed_data: Vec<u8>) -> Result<()> {
+        let stream = Stream::new(compressed_data, scope)?;
+        for packet in stream.packets()? {
+            let mut data = packet.encode()?;
+
+            unsafe {
+                classicube_sys::CPE_SendPluginMessage(RELAY_CHANNEL, data.as_mut_ptr());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+thread_local!(
+    static TRANSPORT: RefCell<Option<Box<dyn Transport>>> = Default::default();
+);
+
+/// Installs `transport`, returning the previous one.
+pub fn set_transport(transport: Option<Box<dyn Transport>>) -> Option<Box<dyn Transport>> {
+    TRANSPORT.with_borrow_mut(|option| std::mem::replace(option, transport))
+}
+
+pub fn send(scope: Scope, compressed_data: Vec<u8>) -> Result<()> {
+    TRANSPORT.with_borrow_mut(|option| {
+        let Some(transport) = option.as_mut() else {
+            bail!("no transport installed");
+        };
+        transport.send(scope, compressed_data)
+    })
+}
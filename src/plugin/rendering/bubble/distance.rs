@@ -0,0 +1,105 @@
+//! Camera-distance culling, fading and minimum on-screen size.
+//!
+//! Bubbles past `max_distance` (when set) are skipped entirely (no transform
+//! update, no draw calls); the last `fade_distance` before that ramps their
+//! alpha down so they don't pop. `min_size` scales far bubbles up so a line
+//! of text stays roughly that many pixels tall.
+
+use classicube_sys::{Camera, Game, Gfx, Vec3};
+
+use super::{easing::smoothstep, inner::BUBBLE_HEIGHT};
+use crate::plugin::settings::Settings;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DistanceView {
+    /// Multiplier for the bubble's own alpha.
+    pub alpha: f32,
+    /// Extra scale on top of the fixed pixel-to-world ratio; `1.0` unless
+    /// `min_size` kicked in.
+    pub scale: f32,
+}
+
+/// `None` when `anchor` is too far away to draw. The minimum size is checked
+/// against a single-line bubble, so multi-line bubbles scale the same as
+/// short ones from the same distance.
+pub fn view(anchor: Vec3, settings: &Settings) -> Option<DistanceView> {
+    // `row2.y` of a perspective projection is `1 / tan(fov_y / 2)`, i.e. how
+    // many half-viewports one world unit at distance 1 covers.
+    let pixels_per_unit = unsafe { Gfx.Projection.row2.y * Game.Height as f32 / 2.0 };
+    let distance = camera_distance(anchor);
+
+    let alpha = fade_alpha(distance, settings.max_distance, settings.fade_distance)?;
+    let scale = min_size_scale(
+        distance,
+        BUBBLE_HEIGHT,
+        settings.min_size.into(),
+        pixels_per_unit,
+    );
+    Some(DistanceView { alpha, scale })
+}
+
+/// Whether something `distance` from the camera is close enough to draw.
+pub fn in_range(distance: f32, settings: &Settings) -> bool {
+    settings.max_distance <= 0.0 || distance < settings.max_distance
+}
+
+pub fn camera_distance(point: Vec3) -> f32 {
+    let camera = unsafe { Camera.CurrentPos };
+    let (dx, dy, dz) = (point.x - camera.x, point.y - camera.y, point.z - camera.z);
+    (dx * dx + dy * dy + dz * dz).sqrt()
+}
+
+/// 1 up to `max - fade`, easing to 0 at `max`, `None` beyond. A `max` of 0
+/// never culls.
+fn fade_alpha(distance: f32, max: f32, fade: f32) -> Option<f32> {
+    if max <= 0.0 {
+        return Some(1.0);
+    }
+    if distance >= max {
+        return None;
+    }
+    let fade = fade.min(max);
+    if fade <= 0.0 {
+        return Some(1.0);
+    }
+    let t = (max - distance) / fade;
+    Some(smoothstep(t.clamp(0.0, 1.0)))
+}
+
+/// How much to enlarge a bubble `world_height` tall at `distance` so it
+/// projects to at least `min_pixels` on screen. `min_pixels` of 0 disables.
+fn min_size_scale(distance: f32, world_height: f32, min_pixels: f32, pixels_per_unit: f32) -> f32 {
+    if min_pixels <= 0.0 || distance <= 0.0 || world_height <= 0.0 || pixels_per_unit <= 0.0 {
+        return 1.0;
+    }
+    let projected = world_height * pixels_per_unit / distance;
+    (min_pixels / projected).max(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fade_alpha, min_size_scale};
+
+    #[test]
+    fn fades_in_the_last_stretch_and_culls_past_max() {
+        assert_eq!(fade_alpha(10.0, 48.0, 8.0), Some(1.0));
+        assert_eq!(fade_alpha(40.0, 48.0, 8.0), Some(1.0));
+        let mid = fade_alpha(44.0, 48.0, 8.0).unwrap();
+        assert!(mid > 0.0 && mid < 1.0);
+        assert_eq!(fade_alpha(48.0, 48.0, 8.0), None);
+        assert_eq!(fade_alpha(100.0, 48.0, 8.0), None);
+        // No fade band: hard cutoff.
+        assert_eq!(fade_alpha(47.9, 48.0, 0.0), Some(1.0));
+        // No limit.
+        assert_eq!(fade_alpha(1000.0, 0.0, 8.0), Some(1.0));
+    }
+
+    #[test]
+    fn scales_up_only_when_below_min_size() {
+        // 0.5 units tall, 500 px per unit at distance 1.
+        assert_eq!(min_size_scale(5.0, 0.5, 20.0, 500.0), 1.0);
+        // At 50 units it projects to 5 px; needs 4x to reach 20.
+        assert!((min_size_scale(50.0, 0.5, 20.0, 500.0) - 4.0).abs() < 1e-4);
+        assert_eq!(min_size_scale(50.0, 0.5, 0.0, 500.0), 1.0);
+    }
+}
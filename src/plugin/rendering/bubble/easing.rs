@@ -17,6 +17,18 @@ pub fn smoothstep(t: f32) -> f32 {
     t * t * (3.0 - 2.0 * t)
 }
 
+/// Up and back down as `t` goes 0..1, like a tossed ball: quick off the
+/// ground, hanging at the top. 0 outside that range.
+pub fn bounce(t: f32) -> f32 {
+    if !(0.0..1.0).contains(&t) {
+        0.0
+    } else if t < 0.5 {
+        ease_out_cubic(t * 2.0)
+    } else {
+        1.0 - ease_in_cubic((t - 0.5) * 2.0)
+    }
+}
+
 /// Frame-rate-independent exponential decay factor. Returns the fraction of
 /// the remaining distance to cover this frame, given a time-constant `tau`
 /// (seconds to cover ~63% of the gap).
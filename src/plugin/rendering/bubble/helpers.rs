@@ -1,16 +1,22 @@
-use std::{cell::RefCell, mem, os::raw::c_int, slice};
+use std::{cell::RefCell, mem, os::raw::c_int, rc::Rc, slice};
 
 use anyhow::{Error, Result};
 use classicube_helpers::entities::Entity;
 use classicube_sys::{
-    Bitmap, Context2D, Context2D_DrawPixels, Context2D_DrawText, DrawTextArgs, Drawer2D_TextHeight,
+    Context2D, Context2D_DrawPixels, Context2D_DrawText, DrawTextArgs, Drawer2D_TextHeight,
     Drawer2D_TextWidth, FONT_FLAGS_FONT_FLAGS_NONE, Font_Free, Font_Make, FontDesc, Gfx,
-    Math_NextPowOf2, OwnedContext2D, OwnedString, OwnedTexture, PackedCol, TextureRec, Vec3,
-    cc_int16,
+    Math_NextPowOf2, OwnedContext2D, OwnedString, PackedCol, Vec3, cc_int16,
 };
 use tracing::{debug, warn};
 
-use crate::bubble_image_parts::*;
+use super::skin::{self, Part, Skin};
+use crate::{
+    bubble_image_parts::*,
+    plugin::{
+        rendering::context::atlas::{self, AtlasTextures, TextureCache},
+        settings,
+    },
+};
 
 const BACK_FILL: PackedCol = 0;
 
@@ -26,23 +32,49 @@ pub enum BubbleStyle {
 }
 
 thread_local!(
-    static FONT: RefCell<Option<FontDesc>> = const { RefCell::new(None) };
+    static FONT: RefCell<Option<(u8, FontDesc)>> = const { RefCell::new(None) };
 );
 
+/// Bakes kept after their last bubble goes away.
+const CACHE_CAPACITY: usize = 64;
+
+struct Cache {
+    /// What the cached bakes were drawn with; a change empties the cache.
+    drawn_with: Option<(Rc<Skin>, u8)>,
+    textures: TextureCache<(Vec<String>, BubbleStyle)>,
+}
+
+thread_local!(
+    static CACHE: RefCell<Cache> = RefCell::new(Cache {
+        drawn_with: None,
+        textures: TextureCache::new(CACHE_CAPACITY),
+    });
+);
+
+/// Made on first use and remade whenever `font_size` changes.
 fn with_font<R>(f: impl FnOnce(&mut FontDesc) -> R) -> R {
+    let size = settings::get().font_size;
     FONT.with_borrow_mut(|slot| {
-        let font = slot.get_or_insert_with(|| unsafe {
+        if let Some((old_size, mut font)) = slot.take_if(|(old_size, _)| *old_size != size) {
+            debug!(?old_size, ?size, "font size changed");
+            unsafe { Font_Free(&mut font) };
+        }
+        let (_, font) = slot.get_or_insert_with(|| unsafe {
             let mut font = mem::zeroed();
-            Font_Make(&mut font, 8, FONT_FLAGS_FONT_FLAGS_NONE as _);
-            font
+            Font_Make(&mut font, size.into(), FONT_FLAGS_FONT_FLAGS_NONE as _);
+            (size, font)
         });
         f(font)
     })
 }
 
 pub fn free() {
+    CACHE.with_borrow_mut(|cache| {
+        cache.drawn_with = None;
+        cache.textures.clear();
+    });
     FONT.with_borrow_mut(|slot| {
-        if let Some(mut font) = slot.take() {
+        if let Some((_, mut font)) = slot.take() {
             unsafe { Font_Free(&mut font) };
         }
     });
@@ -50,11 +82,15 @@ pub fn free() {
 
 /// Body height a single line of text occupies (before borders) when its
 /// rendered width is non-zero. Matches `text_height.max(12)` for single-line
-/// inputs and lets the world-height scale stay constant across line counts.
+/// inputs at the default font size and lets the world-height scale stay
+/// constant across line counts. Larger fonts grow the bubble in the world.
 pub const SINGLE_LINE_TEXT_HEIGHT: c_int = 12;
 
 /// Total canvas height for a single-line bubble, in pixels. Used to derive the
 /// fixed world-space scale ratio so multi-line bubbles don't squish vertically.
+/// Measured against the compiled-in parts even when a texture pack supplies
+/// its own, so a skin with thicker borders grows the bubble rather than
+/// shrinking the text.
 pub const SINGLE_LINE_CANVAS_HEIGHT: c_int =
     SINGLE_LINE_TEXT_HEIGHT + TOP_HEIGHT as c_int + BOTTOM_CENTER_HEIGHT as c_int + 2;
 
@@ -98,7 +134,7 @@ fn bitmap_fits_gpu(width: c_int, height: c_int) -> bool {
     true
 }
 
-/// returns (front, back), or `None` if the bubble can't be drawn right now:
+/// Returns the front and back faces, or `None` if the bubble can't be drawn right now:
 /// the GPU context is currently lost (e.g. mid-D3D9-device-reset on Windows),
 /// or the resulting bitmap would exceed the backend's texture size limits.
 /// In either case `Gfx_CreateTexture` would return 0 and `OwnedGfxTexture::new`
@@ -106,22 +142,41 @@ fn bitmap_fits_gpu(width: c_int, height: c_int) -> bool {
 /// Oversized inputs are realistic for relayed `InputTextChanged` payloads —
 /// the wire string has no length cap, so a remote sender can wrap into far
 /// more lines than the local input widget would ever produce.
-#[tracing::instrument]
-pub fn create_textures(
-    lines: &[String],
-    style: BubbleStyle,
-) -> Option<(OwnedTexture, OwnedTexture)> {
-    debug!("");
-
+///
+/// Identical `(lines, style)` share one upload through the atlas cache.
+pub fn create_textures(lines: &[String], style: BubbleStyle) -> Option<Rc<AtlasTextures>> {
     if unsafe { Gfx.LostContext } != 0 {
         warn!("Gfx.LostContext set, skipping bubble texture creation");
         return None;
     }
 
+    let skin = skin::current();
+    let font_size = settings::get().font_size;
+    CACHE.with_borrow_mut(|cache| {
+        let stale = cache
+            .drawn_with
+            .as_ref()
+            .is_none_or(|(old_skin, old_size)| {
+                !Rc::ptr_eq(old_skin, &skin) || *old_size != font_size
+            });
+        if stale {
+            cache.textures.clear();
+            cache.drawn_with = Some((skin.clone(), font_size));
+        }
+        cache
+            .textures
+            .get_or_insert_with((lines.to_vec(), style), || bake(lines, style, &skin))
+    })
+}
+
+#[tracing::instrument(skip(skin))]
+fn bake(lines: &[String], style: BubbleStyle, skin: &Skin) -> Option<AtlasTextures> {
+    debug!("");
+
     let bordered = style == BubbleStyle::Bordered;
     let use_shadow: u8 = if bordered { 0 } else { 1 };
 
-    let (mut front_context, mut back_context, width, height) = with_font(|font| {
+    let (front_context, back_context, width, height) = with_font(|font| {
         let strings: Vec<OwnedString> = lines.iter().map(|l| OwnedString::new(l.clone())).collect();
 
         unsafe {
@@ -149,9 +204,9 @@ pub fn create_textures(
 
             let (width, height, text_x) = if bordered {
                 (
-                    max_w + (LEFT_WIDTH as c_int) * 2 + 2,
-                    body_height + (TOP_HEIGHT as c_int) + (BOTTOM_CENTER_HEIGHT as c_int) + 2,
-                    LEFT_WIDTH as c_int + 1,
+                    max_w + skin.left.width * 2 + 2,
+                    body_height + skin.top.height + skin.bottom_center.height + 2,
+                    skin.left.width + 1,
                 )
             } else {
                 // No border chrome; +2 gives a 1px margin on each side so the
@@ -170,7 +225,11 @@ pub fn create_textures(
                 return None;
             }
 
-            let front_fill = if bordered { FRONT_COLOR } else { BACK_FILL };
+            let front_fill = if bordered {
+                skin.front_color
+            } else {
+                BACK_FILL
+            };
             let mut front_context = OwnedContext2D::new_pow_of_2(width, height, front_fill);
             let mut back_context = OwnedContext2D::new_pow_of_2(width, height, BACK_FILL);
 
@@ -192,17 +251,17 @@ pub fn create_textures(
             }
 
             if bordered {
-                draw_parts(front_context.as_context_2d_mut(), width, height);
-                draw_parts(back_context.as_context_2d_mut(), width, height);
+                draw_parts(front_context.as_context_2d_mut(), skin, width, height);
+                draw_parts(back_context.as_context_2d_mut(), skin, width, height);
 
-                // Border PNGs include FRONT_COLOR pixels next to the antialias
+                // Border PNGs include front-color pixels next to the antialias
                 // edge that blend invisibly into the front canvas's fill. On the
                 // transparent back canvas they'd render as an opaque stripe, so
                 // strip them out, leaving just the antialias outline.
                 let back_bitmap = back_context.as_bitmap_mut();
                 let total = (back_bitmap.width * back_bitmap.height) as usize;
                 for px in slice::from_raw_parts_mut(back_bitmap.scan0, total) {
-                    if *px == FRONT_COLOR {
+                    if *px == skin.front_color {
                         *px = BACK_FILL;
                     }
                 }
@@ -214,22 +273,14 @@ pub fn create_textures(
         }
     })?;
 
-    let u2 = width as f32 / front_context.as_bitmap().width as f32;
-    let v2 = height as f32 / front_context.as_bitmap().height as f32;
-
     let position = (-(width as cc_int16 / 2), -(height as cc_int16));
     let size = (width as _, height as _);
-    let rec = TextureRec {
-        u1: 0.0,
-        v1: 0.0,
-        u2,
-        v2,
-    };
-
-    let front_texture = OwnedTexture::new(front_context.as_bitmap_mut(), position, size, rec)?;
-    let back_texture = OwnedTexture::new(back_context.as_bitmap_mut(), position, size, rec)?;
-
-    Some((front_texture, back_texture))
+    Some(atlas::upload(
+        front_context.as_bitmap(),
+        back_context.as_bitmap(),
+        position,
+        size,
+    ))
 }
 
 /// Returns `(eye_world_position, rotation, eye_to_nameplate_offset)`.
@@ -253,157 +304,45 @@ pub fn get_transform(entity: &Entity) -> Result<(Vec3, Vec3, f32)> {
     Ok::<_, Error>((position, rotation, head_top_offset))
 }
 
-unsafe fn draw_parts(context: &mut Context2D, width: c_int, height: c_int) {
+unsafe fn draw_parts(context: &mut Context2D, skin: &Skin, width: c_int, height: c_int) {
     unsafe {
-        // TOP_LEFT_CORNER
-        let mut top_left_corner_pixels = TOP_LEFT_CORNER_PIXELS;
-        Context2D_DrawPixels(
-            context,
-            0,
-            0,
-            &mut Bitmap {
-                scan0: top_left_corner_pixels.as_mut_ptr(),
-                width: TOP_LEFT_CORNER_WIDTH as c_int,
-                height: TOP_LEFT_CORNER_HEIGHT as c_int,
-            },
-        );
+        let mut draw = |part: &Part, x: c_int, y: c_int| {
+            Context2D_DrawPixels(context, x, y, &mut part.as_bitmap());
+        };
 
-        // TOP
-        let mut top_pixels = TOP_PIXELS;
-        for x in (TOP_LEFT_CORNER_WIDTH as c_int)..width {
-            Context2D_DrawPixels(
-                context,
-                x,
-                0,
-                &mut Bitmap {
-                    scan0: top_pixels.as_mut_ptr(),
-                    width: TOP_WIDTH as c_int,
-                    height: TOP_HEIGHT as c_int,
-                },
-            );
+        draw(&skin.top_left_corner, 0, 0);
+        for x in skin.top_left_corner.width..width {
+            draw(&skin.top, x, 0);
         }
-
-        // TOP_LEFT_CORNER flip x
-        let mut top_right_corner_pixels = TOP_LEFT_CORNER_PIXELS;
-        flip_x(
-            &mut top_right_corner_pixels,
-            TOP_LEFT_CORNER_WIDTH as usize,
-            TOP_LEFT_CORNER_HEIGHT as usize,
-        );
-        Context2D_DrawPixels(
-            context,
-            width - TOP_LEFT_CORNER_WIDTH as c_int,
+        draw(
+            &skin.top_right_corner,
+            width - skin.top_right_corner.width,
             0,
-            &mut Bitmap {
-                scan0: top_right_corner_pixels.as_mut_ptr(),
-                width: TOP_LEFT_CORNER_WIDTH as c_int,
-                height: TOP_LEFT_CORNER_HEIGHT as c_int,
-            },
         );
 
-        // LEFT
-        let mut left_pixels = LEFT_PIXELS;
-        for y in TOP_LEFT_CORNER_HEIGHT as usize..height as usize {
-            Context2D_DrawPixels(
-                context,
-                0,
-                y as c_int,
-                &mut Bitmap {
-                    scan0: left_pixels.as_mut_ptr(),
-                    width: LEFT_WIDTH as c_int,
-                    height: LEFT_HEIGHT as c_int,
-                },
-            );
+        for y in skin.top_left_corner.height..height {
+            draw(&skin.left, 0, y);
+            draw(&skin.right, width - skin.right.width, y);
         }
 
-        // LEFT flip x
-        let mut right_pixels = LEFT_PIXELS;
-        flip_x(&mut right_pixels, LEFT_WIDTH as usize, LEFT_HEIGHT as usize);
-        for y in TOP_LEFT_CORNER_HEIGHT as usize..height as usize {
-            Context2D_DrawPixels(
-                context,
-                width - LEFT_WIDTH as c_int,
-                y as c_int,
-                &mut Bitmap {
-                    scan0: right_pixels.as_mut_ptr(),
-                    width: LEFT_WIDTH as c_int,
-                    height: LEFT_HEIGHT as c_int,
-                },
-            );
-        }
-
-        // BOTTOM_LEFT_CORNER
-        let mut bottom_left_corner_pixels = BOTTOM_LEFT_CORNER_PIXELS;
-        Context2D_DrawPixels(
-            context,
+        draw(
+            &skin.bottom_left_corner,
             0,
-            height - BOTTOM_LEFT_CORNER_HEIGHT as c_int,
-            &mut Bitmap {
-                scan0: bottom_left_corner_pixels.as_mut_ptr(),
-                width: BOTTOM_LEFT_CORNER_WIDTH as c_int,
-                height: BOTTOM_LEFT_CORNER_HEIGHT as c_int,
-            },
+            height - skin.bottom_left_corner.height,
         );
-
-        // BOTTOM
-        let mut bottom_pixels = BOTTOM_PIXELS;
-        for x in (BOTTOM_LEFT_CORNER_WIDTH as c_int)..width {
-            Context2D_DrawPixels(
-                context,
-                x,
-                height - BOTTOM_HEIGHT as c_int,
-                &mut Bitmap {
-                    scan0: bottom_pixels.as_mut_ptr(),
-                    width: BOTTOM_WIDTH as c_int,
-                    height: BOTTOM_HEIGHT as c_int,
-                },
-            );
+        for x in skin.bottom_left_corner.width..width {
+            draw(&skin.bottom, x, height - skin.bottom.height);
         }
-
-        // BOTTOM_LEFT_CORNER flip x
-        let mut bottom_right_corner_pixels = BOTTOM_LEFT_CORNER_PIXELS;
-        flip_x(
-            &mut bottom_right_corner_pixels,
-            BOTTOM_LEFT_CORNER_WIDTH as usize,
-            BOTTOM_LEFT_CORNER_HEIGHT as usize,
-        );
-        Context2D_DrawPixels(
-            context,
-            width - BOTTOM_LEFT_CORNER_WIDTH as c_int,
-            height - BOTTOM_LEFT_CORNER_HEIGHT as c_int,
-            &mut Bitmap {
-                scan0: bottom_right_corner_pixels.as_mut_ptr(),
-                width: BOTTOM_LEFT_CORNER_WIDTH as c_int,
-                height: BOTTOM_LEFT_CORNER_HEIGHT as c_int,
-            },
+        draw(
+            &skin.bottom_right_corner,
+            width - skin.bottom_right_corner.width,
+            height - skin.bottom_right_corner.height,
         );
 
-        // BOTTOM_CENTER
-        let mut bottom_center_pixels = BOTTOM_CENTER_PIXELS;
-        flip_x(
-            &mut bottom_center_pixels,
-            BOTTOM_CENTER_WIDTH as usize,
-            BOTTOM_CENTER_HEIGHT as usize,
+        draw(
+            &skin.bottom_center,
+            width / 2 - skin.bottom_center.width / 2,
+            height - skin.bottom_center.height,
         );
-        Context2D_DrawPixels(
-            context,
-            width / 2 - BOTTOM_CENTER_WIDTH as c_int / 2,
-            height - BOTTOM_CENTER_HEIGHT as c_int,
-            &mut Bitmap {
-                scan0: bottom_center_pixels.as_mut_ptr(),
-                width: BOTTOM_CENTER_WIDTH as c_int,
-                height: BOTTOM_CENTER_HEIGHT as c_int,
-            },
-        );
-    }
-}
-
-fn flip_x(c: &mut [PackedCol], w: usize, h: usize) {
-    for x in 0..w / 2 {
-        for y in 0..h {
-            let i1 = y * w + x;
-            let i2 = y * w + w - x - 1;
-            c.swap(i1, i2);
-        }
     }
 }
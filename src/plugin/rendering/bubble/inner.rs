@@ -1,10 +1,14 @@
-use std::os::raw::c_float;
+use std::{os::raw::c_float, rc::Rc};
 
 use classicube_helpers::entities::Entity;
-use classicube_sys::{MATH_DEG2RAD, Matrix, OwnedTexture, Vec3};
+use classicube_sys::{Gfx, MATH_DEG2RAD, Matrix, Vec3};
 use tracing::warn;
 
 use super::helpers::{BubbleStyle, SINGLE_LINE_CANVAS_HEIGHT, create_textures, get_transform};
+use crate::plugin::{
+    rendering::context::atlas::AtlasTextures,
+    settings::{self, Orientation},
+};
 
 // pub const BUBBLE_WIDTH: u8 = 4;
 pub const BUBBLE_HEIGHT: f32 = 0.5;
@@ -15,15 +19,17 @@ pub const BUBBLE_HEIGHT: f32 = 0.5;
 const SCALE_RATIO: f32 = BUBBLE_HEIGHT / SINGLE_LINE_CANVAS_HEIGHT as f32;
 
 pub struct InnerBubble {
-    /// (front, back)
-    pub textures: (OwnedTexture, OwnedTexture),
+    /// Shared with any other bubble showing the same text.
+    pub textures: Rc<AtlasTextures>,
     pub transform: Matrix,
+    style: BubbleStyle,
 }
 impl InnerBubble {
     pub fn new(lines: &[String], style: BubbleStyle) -> Option<InnerBubble> {
         Some(InnerBubble {
             textures: create_textures(lines, style)?,
             transform: Matrix::IDENTITY,
+            style,
         })
     }
 
@@ -32,32 +38,79 @@ impl InnerBubble {
     /// keeping the visual gap between bubbles constant regardless of how
     /// many text lines each one contains.
     pub fn height_world(&self) -> f32 {
-        self.textures.0.as_texture().height as f32 * SCALE_RATIO
+        self.textures.front.height as f32 * SCALE_RATIO
     }
 
-    /// `position` is the eye world position; `y_offset` is applied in the
-    /// bubble's local frame (after the rotate_z flip), so head pitch rotates
-    /// it along with the head. Callers should fold the eye-to-nameplate
-    /// distance (from `get_transform`'s third return value) into `y_offset`
-    /// so the bubble's resting position sits on top of the head.
-    pub fn update_transform(&mut self, position: Vec3, rotation: Vec3, y_offset: f32) {
-        let scale = Vec3::create(SCALE_RATIO, SCALE_RATIO, 1.0);
-
-        let translation = Matrix::translate(position.x, position.y, position.z);
-        let scale = Matrix::scale(scale.x, scale.y, scale.z);
-        let local_up_translation = Matrix::translate(0.0, y_offset, 0.0);
-
-        self.transform = scale
-            * Matrix::rotate_z(180.0 * MATH_DEG2RAD as c_float)
-            * local_up_translation
-            * Matrix::rotate_x(-rotation.x * MATH_DEG2RAD as c_float)
-            * Matrix::rotate_y(-rotation.y * MATH_DEG2RAD as c_float)
-            * translation;
+    /// Turned like a `style` bubble rather than the style it was baked in,
+    /// for pieces drawn on top of another bubble.
+    pub fn oriented_as(mut self, style: BubbleStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    fn orientation(&self) -> Orientation {
+        let settings = settings::get();
+        match self.style {
+            BubbleStyle::Bordered => settings.bordered_orientation,
+            BubbleStyle::Borderless => settings.borderless_orientation,
+        }
+    }
+
+    /// `position` is the eye world position and `head_top_offset` the
+    /// eye-to-nameplate distance (both from `get_transform`). `animation_y`
+    /// is the spawn/fly/stack offset. `scale` enlarges the bubble on top of
+    /// the fixed pixel-to-world ratio (see `distance::view`).
+    ///
+    /// Head-locked, both offsets are applied in the bubble's local frame
+    /// (after the rotate_z flip), so head pitch rotates them along with the
+    /// head. As a billboard the nameplate offset is straight up in the world
+    /// and the animation offset is up on screen, so stacks stay vertical
+    /// from any viewing angle.
+    pub fn update_transform(
+        &mut self,
+        position: Vec3,
+        rotation: Vec3,
+        head_top_offset: f32,
+        animation_y: f32,
+        scale: f32,
+    ) {
+        let scale = Matrix::scale(SCALE_RATIO * scale, SCALE_RATIO * scale, 1.0);
+
+        self.transform = match self.orientation() {
+            Orientation::HeadLocked => {
+                let translation = Matrix::translate(position.x, position.y, position.z);
+                let local_up_translation =
+                    Matrix::translate(0.0, head_top_offset + animation_y, 0.0);
+
+                scale
+                    * Matrix::rotate_z(180.0 * MATH_DEG2RAD as c_float)
+                    * local_up_translation
+                    * Matrix::rotate_x(-rotation.x * MATH_DEG2RAD as c_float)
+                    * Matrix::rotate_y(-rotation.y * MATH_DEG2RAD as c_float)
+                    * translation
+            }
+
+            Orientation::Billboard => {
+                let translation =
+                    Matrix::translate(position.x, position.y + head_top_offset, position.z);
+                let local_up_translation = Matrix::translate(0.0, animation_y, 0.0);
+
+                // Texture space is y-down; flipping y and z (a 180 degree
+                // turn about x) puts it upright and facing back at the
+                // camera once the view rotation is undone.
+                scale
+                    * Matrix::rotate_z(180.0 * MATH_DEG2RAD as c_float)
+                    * Matrix::rotate_y(180.0 * MATH_DEG2RAD as c_float)
+                    * local_up_translation
+                    * inverse_view_rotation()
+                    * translation
+            }
+        };
     }
 
     /// `animation_y` is the spawn/fly/stack offset; this helper adds the
     /// head-top offset on top so the resting bubble sits on the head.
-    pub fn update_transform_entity(&mut self, entity: &Entity, animation_y: f32) {
+    pub fn update_transform_entity(&mut self, entity: &Entity, animation_y: f32, scale: f32) {
         let (position, rotation, head_top_offset) = match get_transform(entity) {
             Ok(ok) => ok,
             Err(e) => {
@@ -65,6 +118,29 @@ impl InnerBubble {
                 return;
             }
         };
-        self.update_transform(position, rotation, animation_y + head_top_offset);
+        self.update_transform(position, rotation, head_top_offset, animation_y, scale);
     }
+
+    /// Moves the bubble `x`, `y` of its own texture pixels (y-down) within
+    /// its plane, after `update_transform` placed it.
+    pub fn offset_pixels(&mut self, x: f32, y: f32) {
+        self.transform = Matrix::translate(x, y, 0.0) * self.transform;
+    }
+}
+
+/// The camera's rotation, undone: the transpose of `Gfx.View`'s upper 3x3
+/// (a rotation's inverse), without its translation.
+fn inverse_view_rotation() -> Matrix {
+    let view = unsafe { Gfx.View };
+    let mut m = Matrix::IDENTITY;
+    m.row1.x = view.row1.x;
+    m.row1.y = view.row2.x;
+    m.row1.z = view.row3.x;
+    m.row2.x = view.row1.y;
+    m.row2.y = view.row2.y;
+    m.row2.z = view.row3.y;
+    m.row3.x = view.row1.z;
+    m.row3.y = view.row2.z;
+    m.row3.z = view.row3.z;
+    m
 }
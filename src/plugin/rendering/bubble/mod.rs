@@ -1,9 +1,12 @@
 #[cfg(test)]
 mod tests;
 
+mod distance;
 mod easing;
-mod helpers;
+pub(crate) mod helpers;
 mod inner;
+mod occlusion;
+mod skin;
 
 // CP437 glyphs for the menu-state icon bubbles. ClassiCube's font is code page
 // 437; OwnedString::new maps these Unicode codepoints back to their CP437 byte
@@ -13,8 +16,13 @@ const DOT: char = '\u{2219}'; // CP437 0xF9
 const CORNER: char = '\u{250C}'; // CP437 0xDA
 const BARS: char = '\u{2261}'; // CP437 0xF0
 
+pub fn initialize() {
+    skin::initialize();
+}
+
 pub fn free() {
     helpers::free();
+    skin::free();
 }
 
 use std::{
@@ -23,41 +31,74 @@ use std::{
     time::{Duration, Instant},
 };
 
-use classicube_helpers::entities::Entity;
-use classicube_sys::{
-    Gfx, Gfx_LoadMatrix, Gfx_SetAlphaArgBlend, Gfx_SetAlphaBlending, Gfx_SetFaceCulling,
-    Gfx_SetTexturing, MatrixType__MATRIX_VIEW, PackedCol_Make, Vec3,
-};
-use tracing::warn;
+use classicube_helpers::entities::{ENTITY_SELF_ID, Entity};
+use classicube_sys::{PackedCol_Make, Vec3};
+use tracing::{debug, warn};
 
 use self::{
-    easing::{clamp01, decay_factor, ease_in_cubic, ease_out_cubic, smoothstep},
+    easing::{bounce, clamp01, decay_factor, ease_in_cubic, ease_out_cubic, smoothstep},
     helpers::BubbleStyle,
     inner::{BUBBLE_HEIGHT, InnerBubble},
 };
-use super::{context::vertex_buffer::Texture_Render, render_hook::renderable::Renderable};
-use crate::plugin::events::{
-    chat_message::{get_chat_prefix, get_nick_name},
-    local_presence::wordwrap::{wrap_for_display, wrap_typing_for_display},
-    player_chat_event::{PlayerChatEvent, Presence, listener::PlayerChatEventListener},
+use super::{context::batch, render_hook::renderable::Renderable};
+use crate::plugin::{
+    events::{
+        chat_message::{get_chat_prefix, get_nick_name, get_player_name, strip_color_codes},
+        history,
+        local_presence::{
+            TYPING_PLACEHOLDER,
+            wordwrap::{wrap_for_display, wrap_typing_for_display},
+        },
+        player_chat_event::{PlayerChatEvent, Presence, listener::PlayerChatEventListener},
+    },
+    networking::{message::Capabilities, peers},
+    settings::{self, Occlusion, Settings, TypingIndicator},
 };
 
-const MESSAGE_LIFETIME: Duration = Duration::from_secs(5);
-const SPAWN_DURATION: Duration = Duration::from_millis(200);
-const FLY_AWAY_DURATION: Duration = Duration::from_millis(400);
 const SPAWN_RISE: f32 = 0.15;
 const FLY_AWAY_RISE: f32 = 0.30;
-/// How much an older bubble's bottom (tail) overlaps the newer bubble's top
-/// edge, in world units. Constant across single- and multi-line bubbles so
-/// the visual gap stays consistent. Tuned to the original single-line look
-/// (`BUBBLE_HEIGHT 0.5 - STACK_OVERLAP 0.20 = 0.30` advance).
-const STACK_OVERLAP: f32 = 0.20;
 const STACK_TWEEN_TAU: f32 = 0.08;
 
+/// `[zZ]` for the first minute, then `[zZ 5m]` / `[zZ 2h]`.
+fn afk_icon(elapsed: Duration) -> String {
+    let minutes = elapsed.as_secs() / 60;
+    let label = match minutes {
+        0 => String::new(),
+        1..60 => format!(" &f{minutes}m"),
+        _ => format!(" &f{}h", minutes / 60),
+    };
+    format!("&f[&7zZ{label}&f]")
+}
+
+/// Blank bordered bubble the typing dots bounce in; as wide as three dots.
+const DOTS_FRAME: &str = "      ";
+const DOT_COUNT: usize = 3;
+/// Seconds for one wave across the dots.
+const DOTS_PERIOD: f32 = 1.2;
+/// Share of the period each dot spends in the air.
+const DOT_HOP: f32 = 0.5;
+/// Delay from one dot to the next, as a share of the period.
+const DOT_STAGGER: f32 = 0.15;
+/// Hop height, in dot heights.
+const DOT_RISE: f32 = 0.35;
+
+/// How high dot `index` is, in dot heights, `elapsed` seconds into the
+/// animation.
+fn dot_hop(elapsed: f32, index: usize) -> f32 {
+    let phase = (elapsed / DOTS_PERIOD - index as f32 * DOT_STAGGER).rem_euclid(1.0);
+    DOT_RISE * bounce(phase / DOT_HOP)
+}
+
 struct Message {
     spawn_instant: Instant,
     die_instant: Instant,
     inner: InnerBubble,
+    /// Server lines `inner` shows, before any repeat counter.
+    lines: Vec<String>,
+    /// How many times in a row this was said; shown from 2 up.
+    repeats: u32,
+    /// A `/me` action, drawn as narration rather than speech.
+    action: bool,
     /// Eye world position snapshotted at message-creation time. Sent bubbles
     /// stay anchored where the player was when they spoke (unlike the status
     /// bubble, which follows the player live).
@@ -73,49 +114,378 @@ struct Message {
 pub struct Bubble {
     entity: Weak<Entity>,
     status: Option<InnerBubble>,
+    /// Lines/style `status` was baked from, so a heartbeat repeating the same
+    /// presence (or an AFK label that hasn't ticked over) skips the re-bake.
+    status_key: Option<(Vec<String>, BubbleStyle)>,
+    /// Latest relayed presence.
+    status_presence: Option<Presence>,
+    /// Last time a `PresenceChanged` arrived, repeated or not.
+    status_refreshed: Option<Instant>,
+    /// Estimated start of a relayed `Presence::Afk`, kept from the first
+    /// report so later heartbeats don't make the label jitter.
+    afk_since: Option<Instant>,
+    /// Set by the server's AFK announcements; shown when there's no relayed
+    /// presence, which covers players without the plugin.
+    server_afk_since: Option<Instant>,
+    /// `settings::generation()` the status was last baked under.
+    status_generation: u32,
+    /// The dot drawn `DOT_COUNT` times over a `DOTS_FRAME` status, and when
+    /// the animation started.
+    typing_dots: Option<(Instant, InnerBubble)>,
     messages: VecDeque<Message>,
+    /// `/client bubbles history` page open over this player, and the lines
+    /// it was baked from.
+    history: Option<(Vec<String>, InnerBubble)>,
     last_render: Option<Instant>,
 }
 
+/// Time to notice a bubble before reading starts.
+const READ_DELAY: Duration = Duration::from_secs(1);
+
+/// Characters a reader sees, not counting `&x` color codes.
+fn visible_len(line: &str) -> usize {
+    strip_color_codes(line).chars().count()
+}
+
+/// How long `lines` stay up: long enough to read at `reading_speed`, but
+/// between `message_lifetime` and `max_message_lifetime`.
+pub(crate) fn message_lifetime(lines: &[String], settings: &Settings) -> Duration {
+    let min = settings.message_lifetime;
+    let max = settings.max_message_lifetime.max(min);
+    if settings.reading_speed <= 0.0 {
+        return min;
+    }
+    let chars: usize = lines.iter().map(|line| visible_len(line)).sum();
+    let reading = Duration::from_secs_f32(chars as f32 / settings.reading_speed);
+    (READ_DELAY + reading).clamp(min, max)
+}
+
+/// `lines` with a `(xN)` counter on the last one when `repeats` > 1.
+/// (CP437 has no multiplication sign.)
+fn with_repeats(lines: &[String], repeats: u32) -> Vec<String> {
+    let mut lines = lines.to_vec();
+    if let Some(last) = lines.last_mut().filter(|_| repeats > 1) {
+        last.push_str(&format!(" &7(x{repeats})"));
+    }
+    lines
+}
+
+/// `/me` lines as narration: gray and between asterisks, the way chat
+/// writes italics, since the font has none.
+fn action_lines(lines: &[String]) -> Vec<String> {
+    let last = lines.len().saturating_sub(1);
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let open = if i == 0 { "*" } else { "" };
+            let close = if i == last { "&7*" } else { "" };
+            format!("&7{open}{line}{close}")
+        })
+        .collect()
+}
+
+/// The bubble for a message's `lines`: speech is bordered, actions are
+/// borderless narration.
+fn bake_message(lines: &[String], repeats: u32, action: bool) -> Option<InnerBubble> {
+    if action {
+        InnerBubble::new(
+            &with_repeats(&action_lines(lines), repeats),
+            BubbleStyle::Borderless,
+        )
+    } else {
+        InnerBubble::new(&with_repeats(lines, repeats), BubbleStyle::Bordered)
+    }
+}
+
 impl Bubble {
     pub fn new(entity: Weak<Entity>) -> Self {
         Self {
             entity,
             status: Default::default(),
+            status_key: None,
+            status_presence: None,
+            status_refreshed: None,
+            afk_since: None,
+            server_afk_since: None,
+            status_generation: settings::generation(),
+            typing_dots: None,
             messages: Default::default(),
+            history: None,
             last_render: None,
         }
     }
 
-    fn render_inner(inner: &mut InnerBubble, alpha: f32) {
-        let alpha_byte = (clamp01(alpha) * 255.0) as u8;
-        let col = PackedCol_Make(255, 255, 255, alpha_byte);
+    /// Only remote peers that advertise `HEARTBEAT` keep their status fresh;
+    /// the local player's presence is emitted on change only, and older
+    /// peers never repeat themselves.
+    fn status_can_expire(&self) -> bool {
+        self.entity.upgrade().is_some_and(|entity| {
+            let id = entity.get_id();
+            id != ENTITY_SELF_ID
+                && peers::get(id)
+                    .is_some_and(|peer| peer.capabilities.contains(Capabilities::HEARTBEAT))
+        })
+    }
 
-        let front_texture = inner.textures.0.as_texture_mut();
-        let back_texture = inner.textures.1.as_texture_mut();
+    fn expire_stale_status(&mut self, now: Instant) {
+        if self.status_presence.is_none() {
+            return;
+        }
+        let stale = self.status_refreshed.is_some_and(|refreshed| {
+            now.saturating_duration_since(refreshed) > settings::get().status_timeout
+        });
+        if stale && self.status_can_expire() {
+            debug!(presence = ?self.status_presence, "status expired");
+            self.status_presence = None;
+            self.afk_since = None;
+            self.rebake_status(now);
+        }
+    }
+
+    /// Typing shown as bouncing dots: always for the placeholder, so it
+    /// doesn't read as the player literally typing `...`, and for everything
+    /// when the `typing_indicator` setting asks for it.
+    fn shows_typing_dots(&self) -> bool {
+        match &self.status_presence {
+            Some(Presence::Typing(text)) => {
+                text == TYPING_PLACEHOLDER
+                    || (!text.is_empty()
+                        && settings::get().typing_indicator == TypingIndicator::Dots)
+            }
+            _ => false,
+        }
+    }
 
-        for (front, texture) in [(true, front_texture), (false, back_texture)] {
-            unsafe {
-                let m = inner.transform * Gfx.View;
-                Gfx_LoadMatrix(MatrixType__MATRIX_VIEW, &m);
+    fn status_lines(&self, now: Instant) -> Option<(Vec<String>, BubbleStyle)> {
+        if !settings::get().show_icons && !matches!(self.status_presence, Some(Presence::Typing(_)))
+        {
+            return None;
+        }
+        if self.shows_typing_dots() {
+            return Some((vec![DOTS_FRAME.to_string()], BubbleStyle::Bordered));
+        }
+        match &self.status_presence {
+            Some(Presence::Typing(text)) => {
+                // Pre-wrap so the typing preview matches what the server
+                // will send when the player hits enter. Strip the `> `
+                // each continuation line gets — server-received
+                // continuations are already `> `-stripped before reaching
+                // the renderer, so this keeps both display paths consistent.
+                //
+                // Bubbles are per-entity and PresenceChanged is only
+                // emitted on ENTITY_SELF_ID, so `self.entity` is the local
+                // player. We feed a chat-line prefix into the wrap so the
+                // first line's 64-byte budget accounts for the `{nick}: `
+                // the server prepends. Prefer the most recently observed
+                // chat prefix (captures server-only titles/flair) and fall
+                // back to the tab-list nick; singleplayer / pre-tab-list /
+                // never-spoken cases fall back to bare-text wrap.
+                let lines = self
+                    .entity
+                    .upgrade()
+                    .and_then(|e| {
+                        let id = e.get_id();
+                        get_chat_prefix(id).or_else(|| get_nick_name(id))
+                    })
+                    .map(|nick| wrap_typing_for_display(text, &nick))
+                    .unwrap_or_else(|| wrap_for_display(text));
+                if lines.is_empty() {
+                    None
+                } else {
+                    Some((lines, BubbleStyle::Bordered))
+                }
+            }
 
-                Gfx_SetAlphaBlending(1);
-                // D3D9: SELECTARG1 (default) discards vertex Col.A; MODULATE
-                // multiplies it in so the fade-out is visible. No-op on GL/D3D11.
-                Gfx_SetAlphaArgBlend(1);
-                Gfx_SetTexturing(1);
-                Gfx_SetFaceCulling(1);
+            Some(Presence::EscapeMenu) => {
+                Some((vec![format!("&f[&6{CORNER}&f]")], BubbleStyle::Borderless))
+            }
 
-                Texture_Render(texture, col, front);
+            Some(Presence::BlockMenu) => Some((
+                vec![format!("&f[&a{DOT} &s{DOT} &7{DOT}&f]")],
+                BubbleStyle::Borderless,
+            )),
 
-                Gfx_SetFaceCulling(0);
-                Gfx_SetAlphaArgBlend(0);
-                Gfx_SetAlphaBlending(0);
+            Some(Presence::TabList) => {
+                Some((vec![format!("&f[&7{BARS}&f]")], BubbleStyle::Borderless))
+            }
 
-                Gfx_LoadMatrix(MatrixType__MATRIX_VIEW, &raw const Gfx.View);
+            Some(Presence::Afk(_)) | None => {
+                self.afk_since.or(self.server_afk_since).map(|since| {
+                    (
+                        vec![afk_icon(now.saturating_duration_since(since))],
+                        BubbleStyle::Borderless,
+                    )
+                })
             }
         }
     }
+
+    fn rebake_status(&mut self, now: Instant) {
+        if !self.shows_typing_dots() {
+            self.typing_dots = None;
+        } else if self.typing_dots.is_none() {
+            // Turned like the frame so the two stay lined up.
+            self.typing_dots = InnerBubble::new(&[format!("&f{DOT}")], BubbleStyle::Borderless)
+                .map(|dot| (now, dot.oriented_as(BubbleStyle::Bordered)));
+        }
+
+        let key = self.status_lines(now);
+        // A failed bake (`status` None despite a key) falls through and retries.
+        if key == self.status_key && (key.is_none() || self.status.is_some()) {
+            return;
+        }
+        self.status = key
+            .as_ref()
+            .and_then(|(lines, style)| InnerBubble::new(lines, *style));
+        self.status_key = key;
+    }
+
+    /// Where the newest message that isn't flying away was spoken, just
+    /// above the head. `None` while the speaker has nothing to say, or it's
+    /// too far away to be drawn.
+    pub fn speaking_anchor(&self) -> Option<Vec3> {
+        let now = Instant::now();
+        let settings = settings::get();
+        self.messages
+            .iter()
+            .rev()
+            .find(|message| now < message.die_instant)
+            .filter(|message| {
+                distance::in_range(distance::camera_distance(message.position), &settings)
+            })
+            .map(|message| Vec3 {
+                y: message.position.y + message.head_top_offset,
+                ..message.position
+            })
+    }
+
+    /// Folds the newest message into the one before it when they're the
+    /// same and that one hasn't started flying away: bumps its counter and
+    /// restarts its lifetime.
+    fn collapse_repeat(&mut self, now: Instant) {
+        let len = self.messages.len();
+        if len < 2 {
+            return;
+        }
+        let (last, previous) = (&self.messages[len - 1], &self.messages[len - 2]);
+        if last.lines != previous.lines
+            || last.action != previous.action
+            || now >= previous.die_instant
+        {
+            return;
+        }
+        self.messages.pop_back();
+        let Some(previous) = self.messages.back_mut() else {
+            return;
+        };
+        previous.repeats += 1;
+        previous.die_instant = now + message_lifetime(&previous.lines, &settings::get());
+        if let Some(inner) = bake_message(&previous.lines, previous.repeats, previous.action) {
+            previous.inner = inner;
+        }
+    }
+
+    /// Sends the oldest live messages flying away once there are more than
+    /// `max_stack`.
+    fn enforce_stack_cap(&mut self, now: Instant) {
+        let max_stack = usize::from(settings::get().max_stack);
+        if max_stack == 0 {
+            return;
+        }
+        let live = self
+            .messages
+            .iter()
+            .filter(|message| now < message.die_instant)
+            .count();
+        for message in self
+            .messages
+            .iter_mut()
+            .filter(|message| now < message.die_instant)
+            .take(live.saturating_sub(max_stack))
+        {
+            message.die_instant = now;
+        }
+    }
+
+    /// Starts a new message bubble where the speaker is now.
+    fn push_message(&mut self, text: &str, action: bool) {
+        let entity = match self.entity.upgrade() {
+            Some(e) => e,
+            None => {
+                warn!("entity Rc Weak dropped?");
+                return;
+            }
+        };
+        let (position, rotation, head_top_offset) = match helpers::get_transform(&entity) {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("get_transform: {:?}", e);
+                return;
+            }
+        };
+        let lines = vec![text.to_string()];
+        let Some(inner) = bake_message(&lines, 1, action) else {
+            warn!("InnerBubble::new returned None (context lost?), skipping message");
+            return;
+        };
+        let now = Instant::now();
+        self.messages.push_back(Message {
+            spawn_instant: now,
+            die_instant: now + message_lifetime(&lines, &settings::get()),
+            inner,
+            lines,
+            repeats: 1,
+            action,
+            position,
+            rotation,
+            head_top_offset,
+            stack_y: 0.0,
+        });
+        // The bake above is a cache hit when this is a repeat, so
+        // pushing first and folding after costs next to nothing.
+        self.collapse_repeat(now);
+        self.enforce_stack_cap(now);
+    }
+
+    /// Keeps `self.history` in step with the open history view.
+    fn update_history(&mut self) {
+        let page = history::current_view().filter(|page| {
+            self.entity.upgrade().is_some_and(|entity| {
+                get_player_name(entity.get_id()).is_some_and(|own| own.to_lowercase() == page.name)
+            })
+        });
+        let Some(page) = page else {
+            self.history = None;
+            return;
+        };
+        if self
+            .history
+            .as_ref()
+            .is_some_and(|(baked, _)| *baked == page.lines)
+        {
+            return;
+        }
+        self.history = InnerBubble::new(&page.lines, BubbleStyle::Bordered)
+            .map(|inner| (page.lines.clone(), inner));
+    }
+
+    /// `depth` is the speaker's distance from the camera; see `batch::push`.
+    /// `occluded` adds a faint copy drawn through walls.
+    fn render_inner(inner: &InnerBubble, alpha: f32, depth: f32, occluded: bool) {
+        let alpha_byte = (clamp01(alpha) * 255.0) as u8;
+        let col = PackedCol_Make(255, 255, 255, alpha_byte);
+
+        batch::push(&inner.textures.front, col, true, &inner.transform, depth);
+        batch::push(&inner.textures.back, col, false, &inner.transform, depth);
+
+        if occluded {
+            let alpha_byte = (clamp01(alpha * occlusion::DIMMED_ALPHA) * 255.0) as u8;
+            let col = PackedCol_Make(255, 255, 255, alpha_byte);
+            batch::push_see_through(&inner.textures.front, col, true, &inner.transform, depth);
+            batch::push_see_through(&inner.textures.back, col, false, &inner.transform, depth);
+        }
+    }
 }
 
 impl Renderable for Bubble {
@@ -126,9 +496,27 @@ impl Renderable for Bubble {
             None => 0.0,
         };
 
+        self.expire_stale_status(now);
+        let settings = settings::get();
+        let generation = settings::generation();
+        if self.status_generation != generation {
+            // Font size or icon visibility may have changed, neither of which
+            // shows up in the status key; force the re-bake.
+            self.status_generation = generation;
+            self.status_key = None;
+            self.status = None;
+            self.typing_dots = None;
+            self.rebake_status(now);
+        } else if self.afk_since.or(self.server_afk_since).is_some() {
+            // Picks up the elapsed-time label ticking over.
+            self.rebake_status(now);
+        }
+
+        self.update_history();
+
         // Keep bubbles alive through the fly-away phase so they can animate out.
         self.messages
-            .retain(|m| now < m.die_instant + FLY_AWAY_DURATION);
+            .retain(|m| now < m.die_instant + settings.fly_away_duration);
 
         let stack_factor = decay_factor(dt, STACK_TWEEN_TAU);
 
@@ -143,47 +531,129 @@ impl Renderable for Bubble {
             // so their raw height_world would let the next message overlap the
             // icon. Clamp to a full bordered slot; bordered statuses are
             // already >= BUBBLE_HEIGHT so this is a no-op for them.
-            .map(|t| t.height_world().max(BUBBLE_HEIGHT) - STACK_OVERLAP)
+            .map(|t| t.height_world().max(BUBBLE_HEIGHT) - settings.stack_overlap)
             .unwrap_or(0.0);
         let mut y_acc = status_advance;
         for message in self.messages.iter_mut().rev() {
             message.stack_y += (y_acc - message.stack_y) * stack_factor;
-            y_acc += message.inner.height_world() - STACK_OVERLAP;
+            // Clamped like the status above, for borderless actions.
+            y_acc += message.inner.height_world().max(BUBBLE_HEIGHT) - settings.stack_overlap;
         }
 
+        // One depth for everything this speaker shows, so the batch's
+        // back-to-front sort moves their stack as a unit and keeps the
+        // oldest→newest order within it.
+        let entity = self.entity.upgrade();
+        let depth = entity
+            .as_ref()
+            .map(|entity| distance::camera_distance(entity.get_position()));
+        // One raycast per speaker, at the middle of the lowest bubble rather
+        // than their head, so a wall that only hides the head doesn't dim a
+        // stack in plain sight. The rest of the stack shares the answer.
+        let aim_offset = self
+            .status
+            .as_ref()
+            .map(|status| status.height_world() / 2.0)
+            .or_else(|| {
+                self.messages
+                    .back()
+                    .map(|message| message.stack_y + message.inner.height_world() / 2.0)
+            })
+            .or_else(|| {
+                self.history
+                    .as_ref()
+                    .map(|(_, inner)| y_acc + inner.height_world() / 2.0)
+            })
+            .unwrap_or(0.0);
+        let occluded = settings.occlusion == Occlusion::Dimmed
+            && (!self.messages.is_empty() || self.status.is_some() || self.history.is_some())
+            && depth.is_some_and(|depth| distance::in_range(depth, &settings))
+            && entity
+                .as_ref()
+                .and_then(|entity| helpers::get_transform(entity).ok())
+                .is_some_and(|(eye, _, head_top_offset)| {
+                    occlusion::is_occluded(Vec3 {
+                        y: eye.y + head_top_offset + aim_offset,
+                        ..eye
+                    })
+                });
+
         for message in self.messages.iter_mut() {
             let age = (now - message.spawn_instant).as_secs_f32();
-            let spawn_t = clamp01(age / SPAWN_DURATION.as_secs_f32());
+            let spawn_t = clamp01(age / settings.spawn_duration.as_secs_f32().max(f32::EPSILON));
             let spawn_y = -SPAWN_RISE * (1.0 - ease_out_cubic(spawn_t));
 
             let (fly_y, alpha) = if now > message.die_instant {
                 let past = (now - message.die_instant).as_secs_f32();
-                let t = clamp01(past / FLY_AWAY_DURATION.as_secs_f32());
+                let t = clamp01(past / settings.fly_away_duration.as_secs_f32().max(f32::EPSILON));
                 (FLY_AWAY_RISE * ease_in_cubic(t), 1.0 - smoothstep(t))
             } else {
                 (0.0, 1.0)
             };
 
-            let y_offset = spawn_y + fly_y + message.stack_y + message.head_top_offset;
-            message
-                .inner
-                .update_transform(message.position, message.rotation, y_offset);
-            Self::render_inner(&mut message.inner, alpha);
+            // Sent bubbles stay where they were spoken, so each one gets
+            // its own distance check.
+            let Some(view) = distance::view(message.position, &settings) else {
+                continue;
+            };
+            message.inner.update_transform(
+                message.position,
+                message.rotation,
+                message.head_top_offset,
+                (spawn_y + fly_y + message.stack_y) * view.scale,
+                view.scale,
+            );
+            Self::render_inner(
+                &message.inner,
+                alpha * view.alpha,
+                depth.unwrap_or_else(|| distance::camera_distance(message.position)),
+                occluded,
+            );
         }
 
-        // Status bubble renders LAST so it draws on top of the message stack
-        // (depth-write is off, so render order decides overlap). It also
+        // The history page sits on top of the stack and, like the status,
+        // follows the player live.
+        if let (Some((_, inner)), Some(entity), Some(depth)) =
+            (self.history.as_mut(), entity.as_ref(), depth)
+        {
+            if let Some(view) = distance::view(entity.get_position(), &settings) {
+                inner.update_transform_entity(entity, y_acc * view.scale, view.scale);
+                Self::render_inner(inner, view.alpha, depth, occluded);
+            }
+        }
+
+        // Status bubble is pushed LAST so it draws on top of the message stack
+        // (depth-write is off, so draw order decides overlap). It also
         // follows the player live, unlike sent messages.
         if let Some(status) = self.status.as_mut() {
-            let entity = match self.entity.upgrade() {
-                Some(e) => e,
-                None => {
-                    warn!("entity Rc Weak dropped?");
-                    return;
-                }
+            let (Some(entity), Some(depth)) = (entity, depth) else {
+                warn!("entity Rc Weak dropped?");
+                return;
             };
-            status.update_transform_entity(&entity, 0.0);
-            Self::render_inner(status, 1.0);
+            let Some(view) = distance::view(entity.get_position(), &settings) else {
+                return;
+            };
+            status.update_transform_entity(&entity, 0.0, view.scale);
+            Self::render_inner(status, view.alpha, depth, occluded);
+
+            // Same anchor as the frame, then moved into its middle in pixels,
+            // which both share.
+            if let Some((since, dot)) = self.typing_dots.as_mut() {
+                let elapsed = now.saturating_duration_since(*since).as_secs_f32();
+                let frame_height = status.textures.front.height as f32;
+                let width = dot.textures.front.width as f32;
+                let height = dot.textures.front.height as f32;
+                dot.update_transform_entity(&entity, 0.0, view.scale);
+                let anchored = dot.transform;
+                for index in 0..DOT_COUNT {
+                    dot.transform = anchored;
+                    dot.offset_pixels(
+                        (index as f32 - (DOT_COUNT - 1) as f32 / 2.0) * width,
+                        -(frame_height - height) / 2.0 - dot_hop(elapsed, index) * height,
+                    );
+                    Self::render_inner(dot, view.alpha, depth, occluded);
+                }
+            }
         }
     }
 }
@@ -192,88 +662,34 @@ impl PlayerChatEventListener for Bubble {
     fn handle_event(&mut self, event: &PlayerChatEvent) {
         match event {
             PlayerChatEvent::PresenceChanged(opt) => {
-                self.status = match opt {
-                    None => None,
-
-                    Some(Presence::Typing(text)) => {
-                        // Pre-wrap so the typing preview matches what the server
-                        // will send when the player hits enter. Strip the `> `
-                        // each continuation line gets — server-received
-                        // continuations are already `> `-stripped before reaching
-                        // the renderer, so this keeps both display paths consistent.
-                        //
-                        // Bubbles are per-entity and PresenceChanged is only
-                        // emitted on ENTITY_SELF_ID, so `self.entity` is the local
-                        // player. We feed a chat-line prefix into the wrap so the
-                        // first line's 64-byte budget accounts for the `{nick}: `
-                        // the server prepends. Prefer the most recently observed
-                        // chat prefix (captures server-only titles/flair) and fall
-                        // back to the tab-list nick; singleplayer / pre-tab-list /
-                        // never-spoken cases fall back to bare-text wrap.
-                        let lines = self
-                            .entity
-                            .upgrade()
-                            .and_then(|e| {
-                                let id = e.get_id();
-                                get_chat_prefix(id).or_else(|| get_nick_name(id))
-                            })
-                            .map(|nick| wrap_typing_for_display(text, &nick))
-                            .unwrap_or_else(|| wrap_for_display(text));
-                        if lines.is_empty() {
-                            None
-                        } else {
-                            InnerBubble::new(&lines, BubbleStyle::Bordered)
-                        }
-                    }
-
-                    Some(Presence::EscapeMenu) => {
-                        InnerBubble::new(&[format!("&f[&6{CORNER}&f]")], BubbleStyle::Borderless)
-                    }
-
-                    Some(Presence::BlockMenu) => InnerBubble::new(
-                        &[format!("&f[&a{DOT} &s{DOT} &7{DOT}&f]")],
-                        BubbleStyle::Borderless,
+                let now = Instant::now();
+                self.status_refreshed = Some(now);
+                self.afk_since = match (opt, &self.status_presence, self.afk_since) {
+                    (Some(Presence::Afk(_)), Some(Presence::Afk(_)), Some(since)) => Some(since),
+                    (Some(Presence::Afk(secs)), ..) => Some(
+                        now.checked_sub(Duration::from_secs((*secs).into()))
+                            .unwrap_or(now),
                     ),
-
-                    Some(Presence::TabList) => {
-                        InnerBubble::new(&[format!("&f[&7{BARS}&f]")], BubbleStyle::Borderless)
-                    }
+                    _ => None,
                 };
+                self.status_presence = opt.clone();
+                self.rebake_status(now);
             }
 
-            PlayerChatEvent::Message(text) => {
-                let entity = match self.entity.upgrade() {
-                    Some(e) => e,
-                    None => {
-                        warn!("entity Rc Weak dropped?");
-                        return;
-                    }
-                };
-                let (position, rotation, head_top_offset) = match helpers::get_transform(&entity) {
-                    Ok(t) => t,
-                    Err(e) => {
-                        warn!("get_transform: {:?}", e);
-                        return;
-                    }
-                };
-                let Some(inner) =
-                    InnerBubble::new(std::slice::from_ref(text), BubbleStyle::Bordered)
-                else {
-                    warn!("InnerBubble::new returned None (context lost?), skipping message");
-                    return;
-                };
+            PlayerChatEvent::AfkChanged(afk) => {
                 let now = Instant::now();
-                self.messages.push_back(Message {
-                    spawn_instant: now,
-                    die_instant: now + MESSAGE_LIFETIME,
-                    inner,
-                    position,
-                    rotation,
-                    head_top_offset,
-                    stack_y: 0.0,
-                });
+                self.server_afk_since = if *afk {
+                    self.server_afk_since.or(Some(now))
+                } else {
+                    None
+                };
+                self.rebake_status(now);
             }
 
+            PlayerChatEvent::Message(text) => self.push_message(text, false),
+
+            PlayerChatEvent::Action(text) => self.push_message(text, true),
+
             PlayerChatEvent::MessageContinuation(lines) => {
                 // Re-bake the most recent message with the accumulated
                 // server-split lines, keeping spawn/die timing + anchor so the
@@ -288,12 +704,30 @@ impl PlayerChatEventListener for Bubble {
                     warn!("MessageContinuation with no prior message");
                     return;
                 };
-                if let Some(inner) = InnerBubble::new(lines, BubbleStyle::Bordered) {
+                if let Some(inner) = bake_message(lines, last.repeats, last.action) {
                     last.inner = inner;
+                    last.lines.clone_from(lines);
+                    // More to read now; counted from when it first showed.
+                    // Leave it alone if it's already flying away.
+                    if Instant::now() < last.die_instant {
+                        last.die_instant =
+                            last.spawn_instant + message_lifetime(lines, &settings::get());
+                    }
                 } else {
                     warn!("InnerBubble::new returned None (context lost?), keeping prior bubble");
                 }
+                // A repeated long message only matches once it's whole.
+                self.collapse_repeat(Instant::now());
             }
         }
     }
+
+    fn clear(&mut self) {
+        self.messages.clear();
+        self.status_presence = None;
+        self.status_refreshed = None;
+        self.afk_since = None;
+        self.server_afk_since = None;
+        self.rebake_status(Instant::now());
+    }
 }
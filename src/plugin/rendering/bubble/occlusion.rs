@@ -0,0 +1,113 @@
+//! Line-of-sight check for `Occlusion::Dimmed`: walks the blocks between the
+//! camera and a speaker, so only bubbles actually behind a wall get the faint
+//! see-through pass.
+
+use classicube_sys::{Blocks, Camera, DrawType__DRAW_OPAQUE, Vec3, World};
+
+/// Alpha multiplier for the see-through pass.
+pub const DIMMED_ALPHA: f32 = 0.35;
+
+/// Whether an opaque block sits between the camera and `point`.
+pub fn is_occluded(point: Vec3) -> bool {
+    let camera = unsafe { Camera.CurrentPos };
+    ray_hits(camera, point, is_opaque)
+}
+
+fn is_opaque(x: i32, y: i32, z: i32) -> bool {
+    unsafe {
+        if World.Blocks.is_null()
+            || x < 0
+            || y < 0
+            || z < 0
+            || x >= World.Width
+            || y >= World.Height
+            || z >= World.Length
+        {
+            return false;
+        }
+        let index = ((y * World.Length + z) * World.Width + x) as usize;
+        let mut block = usize::from(*World.Blocks.add(index));
+        // Without extended blocks `Blocks2` just aliases `Blocks`.
+        if World.Blocks2 != World.Blocks {
+            block |= usize::from(*World.Blocks2.add(index)) << 8;
+        }
+        block &= World.IDMask as usize;
+        u32::from(Blocks.Draw[block]) == DrawType__DRAW_OPAQUE as u32
+    }
+}
+
+/// Voxel walk (Amanatides & Woo) from `from` to `to`, skipping the block the
+/// ray starts in so a third-person camera clipped into a wall doesn't count.
+fn ray_hits(from: Vec3, to: Vec3, mut solid: impl FnMut(i32, i32, i32) -> bool) -> bool {
+    let origin = [from.x, from.y, from.z];
+    let delta = [to.x - from.x, to.y - from.y, to.z - from.z];
+
+    let mut cell = origin.map(|v| v.floor() as i32);
+    let start = cell;
+    let mut step = [0; 3];
+    let mut t_max = [f32::INFINITY; 3];
+    let mut t_delta = [f32::INFINITY; 3];
+    for axis in 0..3 {
+        let d = delta[axis];
+        if d > 0.0 {
+            step[axis] = 1;
+            t_max[axis] = (cell[axis] as f32 + 1.0 - origin[axis]) / d;
+            t_delta[axis] = 1.0 / d;
+        } else if d < 0.0 {
+            step[axis] = -1;
+            t_max[axis] = (origin[axis] - cell[axis] as f32) / -d;
+            t_delta[axis] = 1.0 / -d;
+        }
+    }
+
+    loop {
+        if cell != start && solid(cell[0], cell[1], cell[2]) {
+            return true;
+        }
+        let axis = (0..3)
+            .min_by(|&a, &b| t_max[a].total_cmp(&t_max[b]))
+            .unwrap_or(0);
+        if t_max[axis] > 1.0 {
+            return false;
+        }
+        cell[axis] += step[axis];
+        t_max[axis] += t_delta[axis];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use classicube_sys::Vec3;
+
+    use super::ray_hits;
+
+    fn v(x: f32, y: f32, z: f32) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    #[test]
+    fn finds_walls_in_the_way() {
+        let wall = |x: i32, _: i32, _: i32| x == 5;
+        assert!(ray_hits(v(0.5, 1.5, 0.5), v(9.5, 1.5, 0.5), wall));
+        assert!(ray_hits(v(0.5, 1.5, 0.5), v(9.5, 3.5, 7.5), wall));
+        // Stops short of the wall.
+        assert!(!ray_hits(v(0.5, 1.5, 0.5), v(4.5, 1.5, 0.5), wall));
+        // Going the other way.
+        assert!(ray_hits(v(9.5, 1.5, 0.5), v(0.5, 1.5, 0.5), wall));
+    }
+
+    #[test]
+    fn ignores_the_starting_block() {
+        let floor = |_: i32, y: i32, _: i32| y == 0;
+        assert!(!ray_hits(v(0.5, 0.5, 0.5), v(0.5, 3.5, 0.5), floor));
+        assert!(ray_hits(v(0.5, 2.5, 0.5), v(0.5, -0.5, 0.5), floor));
+    }
+
+    #[test]
+    fn open_air_is_clear() {
+        let air = |_: i32, _: i32, _: i32| false;
+        assert!(!ray_hits(v(0.0, 0.0, 0.0), v(-3.2, 7.1, 2.0), air));
+        let stone = |_: i32, _: i32, _: i32| true;
+        assert!(!ray_hits(v(1.5, 1.5, 1.5), v(1.5, 1.5, 1.5), stone));
+    }
+}
@@ -0,0 +1,396 @@
+//! The 9-slice bubble frame. Defaults to the parts `build.rs` bakes in from
+//! `bubble_image_parts/*.png`; any part the active texture pack provides
+//! replaces its compiled-in counterpart until the pack changes.
+//!
+//! ClassiCube flattens zip paths to the bare file name before raising
+//! `TextureEvents.FileChanged`, so a pack's `chatbubbles/top_left_corner.png`
+//! arrives as `top_left_corner.png` -- too generic to claim safely. Parts are
+//! therefore matched by a `chatbubbles_` file-name prefix
+//! (`chatbubbles/chatbubbles_top_left_corner.png`, or anywhere in the zip).
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    mem,
+    os::raw::{c_int, c_void},
+    ptr,
+    rc::Rc,
+};
+
+use classicube_sys::{
+    Bitmap, BitmapCol, BitmapCol_A, BitmapCol_B, BitmapCol_G, BitmapCol_R, Event_RegisterEntry,
+    Event_RegisterVoid, Event_UnregisterEntry, Event_UnregisterVoid, Mem_Free, PackedCol,
+    PackedCol_Make, Png_Decode, Stream, TextureEvents, cc_string,
+};
+use tracing::{debug, warn};
+
+use crate::bubble_image_parts::*;
+
+const FILE_PREFIX: &str = "chatbubbles_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum PartName {
+    TopLeftCorner,
+    Top,
+    Left,
+    BottomLeftCorner,
+    Bottom,
+    BottomCenter,
+    Center,
+}
+
+impl PartName {
+    const ALL: [Self; 7] = [
+        Self::TopLeftCorner,
+        Self::Top,
+        Self::Left,
+        Self::BottomLeftCorner,
+        Self::Bottom,
+        Self::BottomCenter,
+        Self::Center,
+    ];
+
+    fn file_stem(self) -> &'static str {
+        match self {
+            Self::TopLeftCorner => "top_left_corner",
+            Self::Top => "top",
+            Self::Left => "left",
+            Self::BottomLeftCorner => "bottom_left_corner",
+            Self::Bottom => "bottom",
+            Self::BottomCenter => "bottom_center",
+            Self::Center => "center",
+        }
+    }
+
+    /// `chatbubbles_top_left_corner.png` → `TopLeftCorner`.
+    fn from_file_name(name: &str) -> Option<Self> {
+        let stem = name
+            .strip_prefix(FILE_PREFIX)?
+            .strip_suffix(".png")?
+            .to_ascii_lowercase();
+        Self::ALL.into_iter().find(|part| part.file_stem() == stem)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Part {
+    pub width: c_int,
+    pub height: c_int,
+    pub pixels: Vec<PackedCol>,
+}
+
+impl Part {
+    fn new(width: u32, height: u32, pixels: &[PackedCol]) -> Self {
+        Self {
+            width: width as c_int,
+            height: height as c_int,
+            pixels: pixels.to_vec(),
+        }
+    }
+
+    /// A decoded PNG. `Png_Decode` gives `BitmapCol`s, whose channel order
+    /// isn't `PackedCol`'s on every platform; the built-in parts are
+    /// `PackedCol_Make`d by `build.rs`, so pack parts are converted to match.
+    fn from_bitmap(width: c_int, height: c_int, pixels: &[BitmapCol]) -> Self {
+        Self {
+            width,
+            height,
+            pixels: pixels
+                .iter()
+                .map(|&col| {
+                    PackedCol_Make(
+                        BitmapCol_R(col),
+                        BitmapCol_G(col),
+                        BitmapCol_B(col),
+                        BitmapCol_A(col),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn flipped_x(&self) -> Self {
+        let mut flipped = self.clone();
+        flip_x(
+            &mut flipped.pixels,
+            self.width as usize,
+            self.height as usize,
+        );
+        flipped
+    }
+
+    /// `Context2D_DrawPixels` only reads the source, so handing it a
+    /// `*mut` into a shared part is fine.
+    pub fn as_bitmap(&self) -> Bitmap {
+        Bitmap {
+            scan0: self.pixels.as_ptr() as *mut _,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+/// Parts ready to composite, with the mirrored right-hand pieces precomputed.
+pub struct Skin {
+    /// Fill behind the text; the first pixel of `center.png`.
+    pub front_color: PackedCol,
+    pub top_left_corner: Part,
+    pub top_right_corner: Part,
+    pub top: Part,
+    pub left: Part,
+    pub right: Part,
+    pub bottom_left_corner: Part,
+    pub bottom_right_corner: Part,
+    pub bottom: Part,
+    /// The tail. Drawn mirrored, as the compiled-in art expects.
+    pub bottom_center: Part,
+}
+
+impl Skin {
+    fn build(overrides: &HashMap<PartName, Part>) -> Self {
+        let part = |name: PartName, builtin: Part| overrides.get(&name).cloned().unwrap_or(builtin);
+
+        let top_left_corner = part(
+            PartName::TopLeftCorner,
+            Part::new(
+                TOP_LEFT_CORNER_WIDTH,
+                TOP_LEFT_CORNER_HEIGHT,
+                &TOP_LEFT_CORNER_PIXELS,
+            ),
+        );
+        let left = part(
+            PartName::Left,
+            Part::new(LEFT_WIDTH, LEFT_HEIGHT, &LEFT_PIXELS),
+        );
+        let bottom_left_corner = part(
+            PartName::BottomLeftCorner,
+            Part::new(
+                BOTTOM_LEFT_CORNER_WIDTH,
+                BOTTOM_LEFT_CORNER_HEIGHT,
+                &BOTTOM_LEFT_CORNER_PIXELS,
+            ),
+        );
+        let bottom_center = part(
+            PartName::BottomCenter,
+            Part::new(
+                BOTTOM_CENTER_WIDTH,
+                BOTTOM_CENTER_HEIGHT,
+                &BOTTOM_CENTER_PIXELS,
+            ),
+        );
+        let front_color = overrides
+            .get(&PartName::Center)
+            .and_then(|center| center.pixels.first().copied())
+            .unwrap_or(FRONT_COLOR);
+
+        Self {
+            front_color,
+            top_right_corner: top_left_corner.flipped_x(),
+            top_left_corner,
+            top: part(PartName::Top, Part::new(TOP_WIDTH, TOP_HEIGHT, &TOP_PIXELS)),
+            right: left.flipped_x(),
+            left,
+            bottom_right_corner: bottom_left_corner.flipped_x(),
+            bottom_left_corner,
+            bottom: part(
+                PartName::Bottom,
+                Part::new(BOTTOM_WIDTH, BOTTOM_HEIGHT, &BOTTOM_PIXELS),
+            ),
+            bottom_center: bottom_center.flipped_x(),
+        }
+    }
+}
+
+thread_local!(
+    static OVERRIDES: RefCell<HashMap<PartName, Part>> = Default::default();
+);
+
+thread_local!(
+    static SKIN: RefCell<Option<Rc<Skin>>> = const { RefCell::new(None) };
+);
+
+/// The skin new bubbles should be baked with. Already-baked bubbles keep the
+/// look they were created with.
+pub fn current() -> Rc<Skin> {
+    SKIN.with_borrow_mut(|skin| {
+        skin.get_or_insert_with(|| {
+            OVERRIDES.with_borrow(|overrides| Rc::new(Skin::build(overrides)))
+        })
+        .clone()
+    })
+}
+
+/// New pack selected: drop the old pack's parts. Its replacements (if any)
+/// follow as `FileChanged` events while the zip is extracted.
+unsafe extern "C" fn on_pack_changed(_obj: *mut c_void) {
+    debug!("texture pack changed, resetting bubble skin");
+    OVERRIDES.with_borrow_mut(|overrides| overrides.clear());
+    SKIN.with_borrow_mut(|skin| skin.take());
+}
+
+unsafe extern "C" fn on_file_changed(
+    _obj: *mut c_void,
+    stream: *mut Stream,
+    name: *const cc_string,
+) {
+    let name = unsafe { (*name).to_string() };
+    let Some(part_name) = PartName::from_file_name(&name) else {
+        return;
+    };
+
+    match unsafe { decode_png(stream) } {
+        Some(part) => {
+            debug!(?name, part.width, part.height, "loaded bubble skin part");
+            OVERRIDES.with_borrow_mut(|overrides| {
+                overrides.insert(part_name, part);
+            });
+            SKIN.with_borrow_mut(|skin| skin.take());
+        }
+        None => warn!(?name, "couldn't decode bubble skin part, keeping built-in"),
+    }
+}
+
+unsafe fn decode_png(stream: *mut Stream) -> Option<Part> {
+    unsafe {
+        let mut bmp: Bitmap = mem::zeroed();
+        let result = Png_Decode(&mut bmp, stream);
+        if bmp.scan0.is_null() {
+            return None;
+        }
+        let part = if result == 0 && bmp.width > 0 && bmp.height > 0 {
+            let len = (bmp.width * bmp.height) as usize;
+            Some(Part::from_bitmap(
+                bmp.width,
+                bmp.height,
+                std::slice::from_raw_parts(bmp.scan0, len),
+            ))
+        } else {
+            None
+        };
+        Mem_Free(bmp.scan0 as *mut c_void);
+        part
+    }
+}
+
+pub fn initialize() {
+    unsafe {
+        Event_RegisterVoid(
+            &mut TextureEvents.PackChanged,
+            ptr::null_mut(),
+            Some(on_pack_changed),
+        );
+        Event_RegisterEntry(
+            &mut TextureEvents.FileChanged,
+            ptr::null_mut(),
+            Some(on_file_changed),
+        );
+    }
+}
+
+pub fn free() {
+    unsafe {
+        Event_UnregisterEntry(
+            &mut TextureEvents.FileChanged,
+            ptr::null_mut(),
+            Some(on_file_changed),
+        );
+        Event_UnregisterVoid(
+            &mut TextureEvents.PackChanged,
+            ptr::null_mut(),
+            Some(on_pack_changed),
+        );
+    }
+    OVERRIDES.with_borrow_mut(|overrides| overrides.clear());
+    SKIN.with_borrow_mut(|skin| skin.take());
+}
+
+fn flip_x(c: &mut [PackedCol], w: usize, h: usize) {
+    for x in 0..w / 2 {
+        for y in 0..h {
+            let i1 = y * w + x;
+            let i2 = y * w + w - x - 1;
+            c.swap(i1, i2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use classicube_sys::{BitmapCol_Make, PackedCol_Make};
+
+    use super::{Part, PartName, Skin};
+    use crate::bubble_image_parts::{FRONT_COLOR, TOP_LEFT_CORNER_WIDTH, TOP_PIXELS};
+
+    #[test]
+    fn matches_prefixed_part_names() {
+        assert_eq!(
+            PartName::from_file_name("chatbubbles_top_left_corner.png"),
+            Some(PartName::TopLeftCorner)
+        );
+        assert_eq!(
+            PartName::from_file_name("chatbubbles_CENTER.png"),
+            Some(PartName::Center)
+        );
+        assert_eq!(PartName::from_file_name("top_left_corner.png"), None);
+        assert_eq!(PartName::from_file_name("chatbubbles_tail.png"), None);
+        assert_eq!(PartName::from_file_name("terrain.png"), None);
+    }
+
+    #[test]
+    fn builtin_skin_without_overrides() {
+        let skin = Skin::build(&HashMap::new());
+        assert_eq!(skin.front_color, FRONT_COLOR);
+        assert_eq!(skin.top_left_corner.width, TOP_LEFT_CORNER_WIDTH as i32);
+    }
+
+    #[test]
+    fn overrides_replace_parts_and_front_color() {
+        let mut overrides = HashMap::new();
+        overrides.insert(
+            PartName::Center,
+            Part {
+                width: 1,
+                height: 1,
+                pixels: vec![0x1234_5678],
+            },
+        );
+        overrides.insert(
+            PartName::TopLeftCorner,
+            Part {
+                width: 2,
+                height: 1,
+                pixels: vec![1, 2],
+            },
+        );
+        let skin = Skin::build(&overrides);
+        assert_eq!(skin.front_color, 0x1234_5678);
+        assert_eq!(skin.top_left_corner.pixels, vec![1, 2]);
+        assert_eq!(skin.top_right_corner.pixels, vec![2, 1]);
+    }
+
+    #[test]
+    fn pack_parts_share_the_builtin_channel_order() {
+        // As `Png_Decode` would hand over an opaque orange.
+        let orange = BitmapCol_Make(255, 128, 0, 255);
+        let mut overrides = HashMap::new();
+        overrides.insert(PartName::Center, Part::from_bitmap(1, 1, &[orange]));
+        overrides.insert(
+            PartName::Left,
+            Part::from_bitmap(2, 1, &[orange, BitmapCol_Make(0, 0, 255, 128)]),
+        );
+        let skin = Skin::build(&overrides);
+
+        // Same layout `build.rs` gives the parts that weren't replaced.
+        assert_eq!(skin.front_color, PackedCol_Make(255, 128, 0, 255));
+        assert_eq!(
+            skin.left.pixels,
+            [
+                PackedCol_Make(255, 128, 0, 255),
+                PackedCol_Make(0, 0, 255, 128)
+            ]
+        );
+        assert_eq!(skin.top.pixels, TOP_PIXELS);
+    }
+}
@@ -1,6 +1,12 @@
+use std::time::Duration;
+
 use classicube_sys::{Convert_CP437ToUnicode, Convert_CodepointToCP437};
 
-use super::{BARS, CORNER, DOT};
+use super::{
+    BARS, CORNER, DOT, DOT_RISE, DOTS_PERIOD, action_lines, afk_icon, dot_hop, easing::bounce,
+    message_lifetime, visible_len, with_repeats,
+};
+use crate::plugin::settings::Settings;
 
 #[test]
 fn icon_glyphs_round_trip_through_cp437() {
@@ -10,3 +16,88 @@ fn icon_glyphs_round_trip_through_cp437() {
         assert_eq!(u32::from(Convert_CP437ToUnicode(byte)), g as u32);
     }
 }
+
+#[test]
+fn afk_icon_labels_elapsed_time() {
+    assert_eq!(afk_icon(Duration::from_secs(30)), "&f[&7zZ&f]");
+    assert_eq!(
+        afk_icon(Duration::from_secs(5 * 60 + 59)),
+        "&f[&7zZ &f5m&f]"
+    );
+    assert_eq!(afk_icon(Duration::from_secs(59 * 60)), "&f[&7zZ &f59m&f]");
+    assert_eq!(
+        afk_icon(Duration::from_secs(2 * 3600 + 5)),
+        "&f[&7zZ &f2h&f]"
+    );
+}
+
+#[test]
+fn repeat_counter_goes_on_the_last_line() {
+    let lines = vec!["spam".to_string(), "more".to_string()];
+    assert_eq!(with_repeats(&lines, 1), lines);
+    assert_eq!(with_repeats(&lines, 3), ["spam", "more &7(x3)"]);
+    assert!(with_repeats(&[], 2).is_empty());
+}
+
+#[test]
+fn color_codes_dont_count_as_text() {
+    assert_eq!(visible_len("&ahello &fworld"), 11);
+    assert_eq!(visible_len("fish & chips &"), 14);
+}
+
+#[test]
+fn lifetime_grows_with_length_within_bounds() {
+    let settings = Settings::default();
+    let short = message_lifetime(&["hi".to_string()], &settings);
+    assert_eq!(short, settings.message_lifetime);
+
+    let line = "x".repeat(60);
+    let one = message_lifetime(std::slice::from_ref(&line), &settings);
+    let two = message_lifetime(&[line.clone(), line.clone()], &settings);
+    assert!(one >= settings.message_lifetime);
+    assert!(two > one);
+
+    let five = message_lifetime(&vec![line.clone(); 5], &settings);
+    assert_eq!(five, settings.max_message_lifetime);
+
+    let flat = Settings {
+        reading_speed: 0.0,
+        ..settings
+    };
+    assert_eq!(
+        message_lifetime(&vec![line; 5], &flat),
+        flat.message_lifetime
+    );
+}
+
+#[test]
+fn bounce_goes_up_and_lands() {
+    assert_eq!(bounce(0.0), 0.0);
+    assert_eq!(bounce(0.5), 1.0);
+    assert_eq!(bounce(1.0), 0.0);
+    assert_eq!(bounce(-0.2), 0.0);
+    assert!(bounce(0.25) > 0.5 && bounce(0.75) > 0.5);
+}
+
+#[test]
+fn typing_dots_hop_one_after_another() {
+    // The first dot is at the top of its hop while the last hasn't left.
+    let first_peak = DOTS_PERIOD * 0.25;
+    assert!((dot_hop(first_peak, 0) - DOT_RISE).abs() < 1e-4);
+    assert!(dot_hop(first_peak, 1) < DOT_RISE);
+    assert_eq!(dot_hop(first_peak, 2), 0.0);
+    // And it repeats.
+    assert!((dot_hop(first_peak + DOTS_PERIOD, 0) - DOT_RISE).abs() < 1e-4);
+    for index in 0..3 {
+        assert_eq!(dot_hop(0.0, index), 0.0);
+    }
+}
+
+#[test]
+fn actions_read_as_narration() {
+    assert_eq!(action_lines(&["waves".to_string()]), ["&7*waves&7*"]);
+    assert_eq!(
+        action_lines(&["waves at".to_string(), "everyone".to_string()]),
+        ["&7*waves at", "&7everyone&7*"]
+    );
+}
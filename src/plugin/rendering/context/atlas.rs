@@ -0,0 +1,571 @@
+//! Bubble bitmaps packed into a few shared GPU textures ("pages"), plus an
+//! LRU cache so identical bakes (presence icons, `...` placeholders, the same
+//! line typed twice) reuse what's already uploaded.
+//!
+//! Each bake takes one region per face. Regions are packed into shelves;
+//! a dropped bake gives its regions back to their shelf's free list, so
+//! long-lived bakes (cached icons, a bubble someone keeps up) don't pin a
+//! whole page. When every shared page is full the bake gets a page of its
+//! own rather than failing.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    hash::Hash,
+    os::raw::c_int,
+    rc::Rc,
+    slice,
+};
+
+use classicube_sys::{
+    Bitmap, BitmapCol, Gfx, Gfx_UpdateTexturePart, Math_NextPowOf2, OwnedGfxTexture, Texture,
+    TextureRec, cc_int16,
+};
+use tracing::debug;
+
+/// Upper bound on a page's side; smaller if the backend can't do it.
+const PAGE_SIZE: c_int = 1024;
+const MAX_SHARED_PAGES: usize = 4;
+/// Transparent gap to the right of and below each region, so filtering at
+/// a region's edge doesn't sample its neighbour.
+const PADDING: c_int = 1;
+
+thread_local!(
+    static PAGES: RefCell<Vec<Rc<RefCell<Page>>>> = Default::default();
+);
+
+thread_local!(
+    /// Bumped when the GPU context is lost; caches drop everything baked
+    /// before it.
+    static EPOCH: Cell<u32> = const { Cell::new(0) };
+);
+
+/// Where `Shelves::allocate` put something, padding included, so it can be
+/// given back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Region {
+    x: c_int,
+    y: c_int,
+    width: c_int,
+}
+
+struct Shelf {
+    y: c_int,
+    height: c_int,
+    /// Unused `(x, width)` runs, sorted by `x` and never touching.
+    free: Vec<(c_int, c_int)>,
+}
+
+impl Shelf {
+    fn new(y: c_int, height: c_int, page_width: c_int) -> Self {
+        Self {
+            y,
+            height,
+            free: vec![(0, page_width)],
+        }
+    }
+
+    fn fits(&self, width: c_int, height: c_int) -> bool {
+        self.height >= height && self.free.iter().any(|&(_, free)| free >= width)
+    }
+
+    /// Leftmost run `width` fits in.
+    fn take(&mut self, width: c_int) -> Option<c_int> {
+        let index = self.free.iter().position(|&(_, free)| free >= width)?;
+        let (x, free) = self.free[index];
+        if free == width {
+            self.free.remove(index);
+        } else {
+            self.free[index] = (x + width, free - width);
+        }
+        Some(x)
+    }
+
+    /// Returns a run, merging it with its neighbours.
+    fn give_back(&mut self, x: c_int, width: c_int) {
+        let index = self.free.partition_point(|&(other, _)| other < x);
+        self.free.insert(index, (x, width));
+        if index + 1 < self.free.len() && x + width == self.free[index + 1].0 {
+            self.free[index].1 += self.free[index + 1].1;
+            self.free.remove(index + 1);
+        }
+        if index > 0 && self.free[index - 1].0 + self.free[index - 1].1 == x {
+            self.free[index - 1].1 += self.free[index].1;
+            self.free.remove(index);
+        }
+    }
+
+    fn is_empty(&self, page_width: c_int) -> bool {
+        self.free == [(0, page_width)]
+    }
+}
+
+/// Shelf packer for one page, kept apart from the GPU texture so it can be
+/// tested.
+struct Shelves {
+    width: c_int,
+    height: c_int,
+    shelves: Vec<Shelf>,
+    live: usize,
+}
+
+impl Shelves {
+    fn new(width: c_int, height: c_int) -> Self {
+        Self {
+            width,
+            height,
+            shelves: Vec::new(),
+            live: 0,
+        }
+    }
+
+    /// A free `width` x `height` region, or `None` if it won't fit. Prefers
+    /// a shelf close to the region's height, then a new shelf, and only then
+    /// a much taller shelf.
+    fn allocate(&mut self, width: c_int, height: c_int) -> Option<Region> {
+        let (width, height) = (width + PADDING, height + PADDING);
+        if width > self.width || height > self.height {
+            return None;
+        }
+
+        let tight = self
+            .shelves
+            .iter()
+            .position(|shelf| shelf.fits(width, height) && shelf.height <= height + height / 2);
+        let index = match tight {
+            Some(index) => index,
+            None => {
+                let y = self
+                    .shelves
+                    .last()
+                    .map_or(0, |shelf| shelf.y + shelf.height);
+                if self.height - y >= height {
+                    self.shelves.push(Shelf::new(y, height, self.width));
+                    self.shelves.len() - 1
+                } else {
+                    self.shelves
+                        .iter()
+                        .position(|shelf| shelf.fits(width, height))?
+                }
+            }
+        };
+
+        let shelf = &mut self.shelves[index];
+        let x = shelf.take(width)?;
+        self.live += 1;
+        Some(Region {
+            x,
+            y: shelf.y,
+            width,
+        })
+    }
+
+    fn release(&mut self, region: Region) {
+        self.live = self.live.saturating_sub(1);
+        if self.live == 0 {
+            self.shelves.clear();
+            return;
+        }
+        if let Some(shelf) = self.shelves.iter_mut().find(|shelf| shelf.y == region.y) {
+            shelf.give_back(region.x, region.width);
+        }
+        // Empty shelves at the bottom go back to being free height, so a
+        // taller shelf can start there.
+        while self
+            .shelves
+            .last()
+            .is_some_and(|shelf| shelf.is_empty(self.width))
+        {
+            self.shelves.pop();
+        }
+    }
+}
+
+/// Finds room for a `size` region on one of `pages`, starting a new page
+/// while there are fewer than `MAX_SHARED_PAGES`. `None` means it needs a
+/// page of its own.
+fn allocate_shared<P>(
+    pages: &mut Vec<Rc<RefCell<P>>>,
+    page_size: (c_int, c_int),
+    new_page: impl FnOnce(c_int, c_int) -> P,
+    shelves: impl Fn(&mut P) -> &mut Shelves,
+    size: (c_int, c_int),
+) -> Option<(Rc<RefCell<P>>, Region)> {
+    for page in pages.iter() {
+        if let Some(region) = shelves(&mut page.borrow_mut()).allocate(size.0, size.1) {
+            return Some((page.clone(), region));
+        }
+    }
+
+    if pages.len() >= MAX_SHARED_PAGES
+        || size.0 + PADDING > page_size.0
+        || size.1 + PADDING > page_size.1
+    {
+        return None;
+    }
+    let page = Rc::new(RefCell::new(new_page(page_size.0, page_size.1)));
+    let region = shelves(&mut page.borrow_mut()).allocate(size.0, size.1)?;
+    pages.push(page.clone());
+    Some((page, region))
+}
+
+struct Page {
+    texture: OwnedGfxTexture,
+    shelves: Shelves,
+}
+
+impl Page {
+    fn new(width: c_int, height: c_int) -> Self {
+        debug!(?width, ?height, "new atlas page");
+        let mut pixels: Vec<BitmapCol> = vec![0; (width * height) as usize];
+        let mut bitmap = Bitmap {
+            scan0: pixels.as_mut_ptr(),
+            width,
+            height,
+        };
+        Self {
+            texture: OwnedGfxTexture::new(&mut bitmap, true, false),
+            shelves: Shelves::new(width, height),
+        }
+    }
+}
+
+/// A baked bubble's faces. Both are plain `Texture`s pointing into a page;
+/// dropping this gives their regions back.
+pub struct AtlasTextures {
+    pub front: Texture,
+    pub back: Texture,
+    regions: [(Rc<RefCell<Page>>, Region); 2],
+}
+
+impl Drop for AtlasTextures {
+    fn drop(&mut self) {
+        for (page, region) in &self.regions {
+            page.borrow_mut().shelves.release(*region);
+        }
+    }
+}
+
+fn page_size() -> (c_int, c_int) {
+    let (max_width, max_height) = unsafe { (Gfx.MaxTexWidth, Gfx.MaxTexHeight) };
+    (PAGE_SIZE.min(max_width), PAGE_SIZE.min(max_height))
+}
+
+/// The first `width` x `height` pixels of `bitmap`, tightly packed.
+fn crop(bitmap: &Bitmap, width: c_int, height: c_int) -> Vec<BitmapCol> {
+    let pixels =
+        unsafe { slice::from_raw_parts(bitmap.scan0, (bitmap.width * bitmap.height) as usize) };
+    (0..height)
+        .flat_map(|row| {
+            let start = (row * bitmap.width) as usize;
+            pixels[start..start + width as usize].iter().copied()
+        })
+        .collect()
+}
+
+fn place(
+    bitmap: &Bitmap,
+    position: (cc_int16, cc_int16),
+    size: (u16, u16),
+) -> (Texture, (Rc<RefCell<Page>>, Region)) {
+    let (width, height) = (c_int::from(size.0), c_int::from(size.1));
+
+    let (page, region) = PAGES.with_borrow_mut(|pages| {
+        allocate_shared(
+            pages,
+            page_size(),
+            Page::new,
+            |page| &mut page.shelves,
+            (width, height),
+        )
+        .unwrap_or_else(|| {
+            // Too big for a shared page, or they're all full. Nothing else
+            // goes on this one, so it needs no padding.
+            debug!(?width, ?height, "bubble gets a page of its own");
+            let mut page = Page::new(Math_NextPowOf2(width), Math_NextPowOf2(height));
+            page.shelves.live = 1;
+            let region = Region { x: 0, y: 0, width };
+            (Rc::new(RefCell::new(page)), region)
+        })
+    });
+    let (x, y) = (region.x, region.y);
+
+    let mut pixels = crop(bitmap, width, height);
+    let mut part = Bitmap {
+        scan0: pixels.as_mut_ptr(),
+        width,
+        height,
+    };
+    let (id, page_width, page_height) = {
+        let page = page.borrow();
+        unsafe {
+            Gfx_UpdateTexturePart(page.texture.resource_id, x, y, &mut part, 0);
+        }
+        (
+            page.texture.resource_id,
+            page.shelves.width as f32,
+            page.shelves.height as f32,
+        )
+    };
+
+    let texture = Texture {
+        ID: id,
+        x: position.0,
+        y: position.1,
+        width: size.0,
+        height: size.1,
+        uv: TextureRec {
+            u1: x as f32 / page_width,
+            v1: y as f32 / page_height,
+            u2: (x + width) as f32 / page_width,
+            v2: (y + height) as f32 / page_height,
+        },
+    };
+    (texture, (page, region))
+}
+
+/// Uploads the top-left `size` of each bitmap. Like `OwnedTexture::new`,
+/// `position` is the quad's offset from the origin it's drawn at.
+pub fn upload(
+    front: &Bitmap,
+    back: &Bitmap,
+    position: (cc_int16, cc_int16),
+    size: (u16, u16),
+) -> AtlasTextures {
+    let (front, front_region) = place(front, position, size);
+    let (back, back_region) = place(back, position, size);
+    AtlasTextures {
+        front,
+        back,
+        regions: [front_region, back_region],
+    }
+}
+
+pub fn epoch() -> u32 {
+    EPOCH.get()
+}
+
+struct CacheEntry<V> {
+    value: Rc<V>,
+    last_used: u64,
+}
+
+/// Keeps up to `capacity` bakes around after their last user lets go,
+/// evicting the least recently used first. Entries still held elsewhere are
+/// never evicted, so a busy map can briefly go over.
+pub struct TextureCache<K, V = AtlasTextures> {
+    entries: HashMap<K, CacheEntry<V>>,
+    capacity: usize,
+    tick: u64,
+    epoch: u32,
+}
+
+impl<K: Hash + Eq + Clone, V> TextureCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            tick: 0,
+            epoch: epoch(),
+        }
+    }
+
+    /// `make` runs on a miss; a `None` from it isn't cached.
+    pub fn get_or_insert_with(
+        &mut self,
+        key: K,
+        make: impl FnOnce() -> Option<V>,
+    ) -> Option<Rc<V>> {
+        if self.epoch != epoch() {
+            self.epoch = epoch();
+            self.clear();
+        }
+        self.tick += 1;
+
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.last_used = self.tick;
+            return Some(entry.value.clone());
+        }
+
+        let value = Rc::new(make()?);
+        self.entries.insert(
+            key,
+            CacheEntry {
+                value: value.clone(),
+                last_used: self.tick,
+            },
+        );
+        self.evict();
+        Some(value)
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let oldest_unused = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| Rc::strong_count(&entry.value) == 1)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            let Some(key) = oldest_unused else {
+                break;
+            };
+            self.entries.remove(&key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+pub fn context_lost() {
+    // Pages still referenced by live bubbles stay alive through their
+    // `AtlasTextures`; new bakes go to fresh pages.
+    PAGES.with_borrow_mut(Vec::clear);
+    EPOCH.set(EPOCH.get().wrapping_add(1));
+}
+
+pub fn free() {
+    PAGES.with_borrow_mut(Vec::clear);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::VecDeque, os::raw::c_int, rc::Rc};
+
+    use super::{PADDING, Region, Shelves, TextureCache, allocate_shared};
+
+    fn at(region: Option<Region>) -> Option<(c_int, c_int)> {
+        region.map(|region| (region.x, region.y))
+    }
+
+    #[test]
+    fn packs_into_shelves_and_resets_when_empty() {
+        let mut shelves = Shelves::new(64, 32);
+        let first = shelves.allocate(20, 10);
+        assert_eq!(at(first), Some((0, 0)));
+        let second = shelves.allocate(20, 10);
+        assert_eq!(at(second), Some((20 + PADDING, 0)));
+        // Doesn't fit beside the first two; starts a new shelf.
+        let third = shelves.allocate(30, 10);
+        assert_eq!(at(third), Some((0, 10 + PADDING)));
+        // Too tall for what's left.
+        assert_eq!(shelves.allocate(10, 20), None);
+        assert_eq!(shelves.allocate(100, 5), None);
+
+        for region in [first, second, third] {
+            shelves.release(region.unwrap());
+        }
+        assert_eq!(at(shelves.allocate(10, 20)), Some((0, 0)));
+    }
+
+    #[test]
+    fn released_regions_are_reused_while_others_live() {
+        let mut shelves = Shelves::new(64, 32);
+        let a = shelves.allocate(20, 10).unwrap();
+        let b = shelves.allocate(20, 10).unwrap();
+        let c = shelves.allocate(20, 10).unwrap();
+        let d = shelves.allocate(20, 10).unwrap();
+        assert_eq!(at(Some(d)), Some((0, 10 + PADDING)));
+
+        // A gap between live neighbours takes something as wide.
+        shelves.release(b);
+        assert_eq!(at(shelves.allocate(20, 10)), Some((b.x, 0)));
+        // Freed neighbours merge into one run.
+        shelves.release(a);
+        shelves.release(c);
+        assert_eq!(shelves.allocate(45, 10), None);
+        shelves.release(d);
+        assert_eq!(at(shelves.allocate(20, 20)), Some((0, 10 + PADDING)));
+    }
+
+    #[test]
+    fn short_regions_prefer_a_new_shelf_over_a_tall_one() {
+        let mut shelves = Shelves::new(64, 64);
+        assert_eq!(at(shelves.allocate(10, 30)), Some((0, 0)));
+        assert_eq!(at(shelves.allocate(10, 5)), Some((0, 30 + PADDING)));
+        // No room for another shelf; the tall one will do.
+        let mut shelves = Shelves::new(64, 32);
+        assert_eq!(at(shelves.allocate(10, 30)), Some((0, 0)));
+        assert_eq!(at(shelves.allocate(10, 5)), Some((10 + PADDING, 0)));
+    }
+
+    /// A bake as the cache and bubbles hold it: its region goes back on
+    /// drop, like `AtlasTextures`.
+    struct Held(Rc<RefCell<Shelves>>, Region);
+
+    impl Drop for Held {
+        fn drop(&mut self) {
+            self.0.borrow_mut().release(self.1);
+        }
+    }
+
+    #[test]
+    fn churn_never_needs_a_page_of_its_own() {
+        let mut pages = Vec::new();
+        let mut cache = TextureCache::<u32, Held>::new(64);
+        // A handful of bubbles up at any time, on top of the cache's own,
+        // plus a few that stay up for good (icons, someone idle).
+        let mut on_screen = VecDeque::new();
+        let mut pinned = Vec::new();
+        for text in 0..5000 {
+            let size = (
+                40 + (text * 37 % 400) as c_int,
+                12 * (1 + text % 3) as c_int,
+            );
+            let held = cache.get_or_insert_with(text, || {
+                let (page, region) =
+                    allocate_shared(&mut pages, (1024, 1024), Shelves::new, |page| page, size)?;
+                Some(Held(page, region))
+            });
+            assert!(held.is_some(), "bake {text} needed a page of its own");
+            if text % 97 == 0 {
+                pinned.push(held.clone());
+            }
+            on_screen.push_back(held);
+            if on_screen.len() > 30 {
+                on_screen.pop_front();
+            }
+        }
+    }
+
+    #[test]
+    fn reuses_hits_and_evicts_least_recently_used() {
+        let mut cache = TextureCache::<&str, u32>::new(2);
+        let a = cache.get_or_insert_with("a", || Some(1)).unwrap();
+        assert!(Rc::ptr_eq(
+            &a,
+            &cache.get_or_insert_with("a", || Some(2)).unwrap()
+        ));
+        drop(a);
+
+        cache.get_or_insert_with("b", || Some(2));
+        // Touch "a" so "b" is the oldest.
+        cache.get_or_insert_with("a", || None);
+        cache.get_or_insert_with("c", || Some(3));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(*cache.get_or_insert_with("a", || Some(9)).unwrap(), 1);
+        assert_eq!(*cache.get_or_insert_with("b", || Some(9)).unwrap(), 9);
+
+        assert!(cache.get_or_insert_with("d", || None).is_none());
+    }
+
+    #[test]
+    fn never_evicts_entries_still_in_use() {
+        let mut cache = TextureCache::<u8, u8>::new(1);
+        let held = [
+            cache.get_or_insert_with(1, || Some(1)).unwrap(),
+            cache.get_or_insert_with(2, || Some(2)).unwrap(),
+        ];
+        assert_eq!(cache.len(), 2);
+        drop(held);
+        cache.get_or_insert_with(3, || Some(3));
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -0,0 +1,293 @@
+//! Every bubble face drawn in a frame, collected and drawn together.
+//!
+//! `push` bakes the bubble's transform into the quad's vertices on the CPU, so
+//! the whole batch draws under the camera's view matrix with one state
+//! setup. Depth-write is off, so draw order decides overlap: quads are sorted
+//! back to front by their speaker's camera distance (stable, so one speaker's
+//! quads keep the order they were pushed in), then consecutive quads sharing
+//! a texture become one upload-and-draw of the shared dynamic vertex buffer.
+//! With the atlas, that's usually a handful of draws per frame however many
+//! players are talking.
+//!
+//! Quads pushed with `push_see_through` draw in a second pass after
+//! everything else, with depth-test off, for `Occlusion::Dimmed`.
+
+use std::cell::RefCell;
+
+use classicube_sys::{
+    Gfx, Gfx_BindTexture, Gfx_LoadMatrix, Gfx_SetAlphaArgBlend, Gfx_SetAlphaBlending,
+    Gfx_SetDepthTest, Gfx_SetFaceCulling, Gfx_SetTexturing, Gfx_SetVertexFormat,
+    Gfx_UpdateDynamicVb_IndexedTris, GfxResourceID, Matrix, MatrixType__MATRIX_VIEW,
+    OwnedGfxVertexBuffer, PackedCol, Texture, VertexFormat__VERTEX_FORMAT_TEXTURED, VertexTextured,
+};
+use tracing::{debug, warn};
+
+use super::vertex_buffer::Gfx_Make2DQuad;
+
+/// ClassiCube's shared quad index buffer covers this many vertices; bigger
+/// groups are drawn in chunks.
+const MAX_VERTICES: usize = 65536;
+const MIN_VERTICES: usize = 256;
+
+struct Quad {
+    depth: f32,
+    texture: GfxResourceID,
+    vertices: [VertexTextured; 4],
+}
+
+#[derive(Default)]
+struct Batch {
+    quads: Vec<Quad>,
+    see_through: Vec<Quad>,
+    /// Grouped copy of `quads`' vertices, kept to reuse its allocation.
+    vertices: Vec<VertexTextured>,
+    buffer: Option<(OwnedGfxVertexBuffer, usize)>,
+}
+
+thread_local!(
+    static BATCH: RefCell<Batch> = Default::default();
+);
+
+/// Row-vector `v * m`; bubble transforms are affine so `w` stays 1.
+fn transform(vertex: &mut VertexTextured, m: &Matrix) {
+    let (x, y, z) = (vertex.x, vertex.y, vertex.z);
+    vertex.x = x * m.row1.x + y * m.row2.x + z * m.row3.x + m.row4.x;
+    vertex.y = x * m.row1.y + y * m.row2.y + z * m.row3.y + m.row4.y;
+    vertex.z = x * m.row1.z + y * m.row2.z + z * m.row3.z + m.row4.z;
+}
+
+fn make_quad(texture: &Texture, col: PackedCol, front: bool, model: &Matrix, depth: f32) -> Quad {
+    let mut vertices = Gfx_Make2DQuad(texture, col, front);
+    for vertex in &mut vertices {
+        transform(vertex, model);
+    }
+    Quad {
+        depth,
+        texture: texture.ID,
+        vertices,
+    }
+}
+
+/// Queue `texture` for drawing at `model` (model-to-world) in this frame's
+/// batch. `front` picks the winding, as in `Texture_Render`. Farther `depth`
+/// draws first; equal depths draw in push order.
+pub fn push(texture: &Texture, col: PackedCol, front: bool, model: &Matrix, depth: f32) {
+    let quad = make_quad(texture, col, front, model, depth);
+    BATCH.with_borrow_mut(|batch| batch.quads.push(quad));
+}
+
+/// Like `push`, but drawn over the world in the see-through pass.
+pub fn push_see_through(
+    texture: &Texture,
+    col: PackedCol,
+    front: bool,
+    model: &Matrix,
+    depth: f32,
+) {
+    let quad = make_quad(texture, col, front, model, depth);
+    BATCH.with_borrow_mut(|batch| batch.see_through.push(quad));
+}
+
+/// Quads sorted back to front into `out`, with runs sharing a texture merged.
+/// Returns `(texture, vertex count)` per run, in draw order.
+fn group(quads: &mut [Quad], out: &mut Vec<VertexTextured>) -> Vec<(GfxResourceID, usize)> {
+    quads.sort_by(|a, b| b.depth.total_cmp(&a.depth));
+    out.clear();
+    let mut groups: Vec<(GfxResourceID, usize)> = Vec::new();
+    for quad in quads.iter() {
+        out.extend_from_slice(&quad.vertices);
+        match groups.last_mut() {
+            Some((texture, count)) if *texture == quad.texture => *count += 4,
+            _ => groups.push((quad.texture, 4)),
+        }
+    }
+    groups
+}
+
+fn ensure_buffer(
+    buffer: &mut Option<(OwnedGfxVertexBuffer, usize)>,
+    vertices: usize,
+) -> Option<&(OwnedGfxVertexBuffer, usize)> {
+    let needed = vertices.clamp(MIN_VERTICES, MAX_VERTICES);
+    if buffer
+        .as_ref()
+        .is_none_or(|(_, capacity)| *capacity < needed)
+    {
+        let capacity = needed.next_power_of_two().min(MAX_VERTICES);
+        debug!(?capacity, "growing bubble vertex buffer");
+        // Drop the old one first so both never exist at once.
+        *buffer = None;
+        *buffer = OwnedGfxVertexBuffer::new(VertexFormat__VERTEX_FORMAT_TEXTURED, capacity as _)
+            .map(|vb| (vb, capacity));
+    }
+    buffer.as_ref()
+}
+
+unsafe fn draw(
+    vb: &OwnedGfxVertexBuffer,
+    capacity: usize,
+    vertices: &mut [VertexTextured],
+    groups: Vec<(GfxResourceID, usize)>,
+) {
+    let mut start = 0;
+    for (texture, count) in groups {
+        unsafe {
+            Gfx_BindTexture(texture);
+            for chunk in vertices[start..start + count].chunks_mut(capacity) {
+                Gfx_UpdateDynamicVb_IndexedTris(
+                    vb.resource_id,
+                    chunk.as_mut_ptr() as _,
+                    chunk.len() as _,
+                );
+            }
+        }
+        start += count;
+    }
+}
+
+/// Draw and clear everything pushed since the last flush. Expects the 3D
+/// projection and depth state `render_hook` sets up for bubbles.
+pub fn flush() {
+    BATCH.with_borrow_mut(|batch| {
+        if batch.quads.is_empty() && batch.see_through.is_empty() {
+            return;
+        }
+        if unsafe { Gfx.LostContext } != 0 {
+            batch.quads.clear();
+            batch.see_through.clear();
+            return;
+        }
+
+        let Batch {
+            quads,
+            see_through,
+            vertices,
+            buffer,
+        } = batch;
+        let Some((vb, capacity)) = ensure_buffer(buffer, quads.len().max(see_through.len()) * 4)
+        else {
+            warn!("couldn't create bubble vertex buffer");
+            quads.clear();
+            see_through.clear();
+            return;
+        };
+
+        unsafe {
+            Gfx_LoadMatrix(MatrixType__MATRIX_VIEW, &raw const Gfx.View);
+            Gfx_SetAlphaBlending(1);
+            // D3D9: SELECTARG1 (default) discards vertex Col.A; MODULATE
+            // multiplies it in so the fade-out is visible. No-op on GL/D3D11.
+            Gfx_SetAlphaArgBlend(1);
+            Gfx_SetTexturing(1);
+            Gfx_SetFaceCulling(1);
+            Gfx_SetVertexFormat(VertexFormat__VERTEX_FORMAT_TEXTURED);
+
+            let groups = group(quads, vertices);
+            draw(vb, *capacity, vertices, groups);
+            quads.clear();
+
+            if !see_through.is_empty() {
+                // Only pushed in `Occlusion::Dimmed`, whose main pass is
+                // depth-tested.
+                Gfx_SetDepthTest(0);
+                let groups = group(see_through, vertices);
+                draw(vb, *capacity, vertices, groups);
+                see_through.clear();
+                Gfx_SetDepthTest(1);
+            }
+
+            Gfx_SetFaceCulling(0);
+            Gfx_SetAlphaArgBlend(0);
+            Gfx_SetAlphaBlending(0);
+        }
+    });
+}
+
+pub fn context_lost() {
+    BATCH.with_borrow_mut(|batch| {
+        batch.quads.clear();
+        batch.see_through.clear();
+        batch.buffer = None;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use classicube_sys::{Matrix, Texture, TextureRec};
+
+    use super::{Quad, group, transform};
+    use crate::plugin::rendering::context::vertex_buffer::Gfx_Make2DQuad;
+
+    fn quad(texture: usize, x: f32, depth: f32) -> Quad {
+        let mut vertices = Gfx_Make2DQuad(
+            &Texture {
+                ID: ptr::without_provenance_mut(texture),
+                x: 0,
+                y: 0,
+                width: 1,
+                height: 1,
+                uv: TextureRec {
+                    u1: 0.0,
+                    v1: 0.0,
+                    u2: 1.0,
+                    v2: 1.0,
+                },
+            },
+            0,
+            true,
+        );
+        for vertex in &mut vertices {
+            vertex.x += x;
+        }
+        Quad {
+            depth,
+            texture: ptr::without_provenance_mut(texture),
+            vertices,
+        }
+    }
+
+    fn draw_order(quads: &mut [Quad]) -> (Vec<(usize, usize)>, Vec<f32>) {
+        let mut vertices = Vec::new();
+        let groups = group(quads, &mut vertices)
+            .into_iter()
+            .map(|(texture, count)| (texture as usize, count))
+            .collect();
+        let xs = vertices.iter().step_by(4).map(|v| v.x).collect();
+        (groups, xs)
+    }
+
+    #[test]
+    fn draws_back_to_front_keeping_push_order_for_ties() {
+        let mut quads = vec![
+            quad(1, 0.0, 5.0),
+            quad(1, 1.0, 20.0),
+            quad(1, 2.0, 5.0),
+            quad(1, 3.0, 10.0),
+        ];
+        let (groups, xs) = draw_order(&mut quads);
+        assert_eq!(groups, [(1, 16)]);
+        assert_eq!(xs, [1.0, 3.0, 0.0, 2.0]);
+    }
+
+    #[test]
+    fn merges_only_consecutive_runs_of_a_texture() {
+        let mut quads = vec![
+            quad(1, 0.0, 30.0),
+            quad(1, 1.0, 30.0),
+            quad(2, 2.0, 20.0),
+            quad(1, 3.0, 10.0),
+        ];
+        let (groups, _) = draw_order(&mut quads);
+        assert_eq!(groups, [(1, 8), (2, 4), (1, 4)]);
+    }
+
+    #[test]
+    fn bakes_the_model_transform_into_vertices() {
+        let mut vertex = quad(1, 0.0, 0.0).vertices[2];
+        let m = Matrix::scale(2.0, 3.0, 1.0) * Matrix::translate(10.0, 20.0, 30.0);
+        transform(&mut vertex, &m);
+        assert_eq!((vertex.x, vertex.y, vertex.z), (12.0, 23.0, 30.0));
+    }
+}
@@ -1,3 +1,5 @@
+pub mod atlas;
+pub mod batch;
 pub mod vertex_buffer;
 
 use std::cell::RefCell;
@@ -26,6 +28,8 @@ pub fn initialize() {
         let mut handler = ContextLostEventHandler::new();
         handler.on(|_| {
             vertex_buffer::context_lost();
+            batch::context_lost();
+            atlas::context_lost();
         });
 
         *option = Some(handler);
@@ -43,4 +47,6 @@ pub fn free() {
         option.take();
     });
     vertex_buffer::context_lost();
+    batch::context_lost();
+    atlas::free();
 }
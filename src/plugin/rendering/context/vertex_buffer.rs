@@ -49,7 +49,7 @@ pub fn context_lost() {
 }
 
 /// clockwise verts (for backface culling), differs from ClassiCube source!
-pub fn Gfx_Make2DQuad(tex: &mut Texture, color: PackedCol, clockwise: bool) -> [VertexTextured; 4] {
+pub fn Gfx_Make2DQuad(tex: &Texture, color: PackedCol, clockwise: bool) -> [VertexTextured; 4] {
     let x1: f32 = tex.x as _;
     let x2: f32 = (tex.x as f32 + tex.width as f32) as _;
     let y1: f32 = tex.y as _;
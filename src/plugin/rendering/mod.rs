@@ -8,11 +8,13 @@ use std::{
     rc::{Rc, Weak},
 };
 
-use classicube_helpers::entities::{Entities, Entity};
+use classicube_helpers::entities::{ENTITY_SELF_ID, Entities, Entity};
+use classicube_sys::Vec3;
 use tracing::debug;
 
 use crate::plugin::{
     events::player_chat_event::listener::StartStopListening,
+    networking::{peers, rate_limit},
     rendering::{bubble::Bubble, render_hook::renderable::StartStopRendering},
 };
 
@@ -27,6 +29,7 @@ thread_local!(
 pub fn initialize() {
     context::initialize();
     render_hook::initialize();
+    bubble::initialize();
 
     ENTITIES.with_borrow_mut(|option| {
         let mut entities = Entities::new();
@@ -48,6 +51,10 @@ pub fn initialize() {
 
         entities.on_removed(|id| {
             debug!(?id, "remove");
+            // Entity ids get reused; don't let the next player inherit this
+            // one's advertised capabilities or throttle state.
+            peers::forget(*id);
+            rate_limit::forget(*id);
 
             BUBBLES.with_borrow_mut(move |map| {
                 if let Some(bubble) = map.remove(id) {
@@ -64,6 +71,35 @@ pub fn initialize() {
     });
 }
 
+/// Remote players with a live message, and where it was spoken.
+pub fn speaking_anchors() -> Vec<(u8, Vec3)> {
+    BUBBLES.with_borrow(|map| {
+        map.iter()
+            .filter(|(id, _)| **id != ENTITY_SELF_ID)
+            .filter_map(|(id, bubble)| Some((*id, bubble.borrow().speaking_anchor()?)))
+            .collect()
+    })
+}
+
+/// Whether entity `id` is in the world with a bubble to draw on.
+pub fn has_bubble(id: u8) -> bool {
+    BUBBLES.with_borrow(|map| map.contains_key(&id))
+}
+
+/// Name tag text of every entity in the world, colors included.
+pub fn display_names() -> Vec<(u8, String)> {
+    ENTITIES.with_borrow(|option| {
+        let Some(entities) = option.as_ref() else {
+            return Vec::new();
+        };
+        entities
+            .get_all()
+            .into_iter()
+            .filter_map(|(id, entity)| Some((id, entity.upgrade()?.get_display_name())))
+            .collect()
+    })
+}
+
 pub fn free() {
     // Drop ENTITIES first so its on_added/on_removed callbacks stop firing
     // before we drain BUBBLES out from under them.
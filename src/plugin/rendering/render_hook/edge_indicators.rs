@@ -0,0 +1,145 @@
+//! 2D overlay marking speakers whose bubble is off-screen: a small
+//! borderless label with their nick and an arrow, pinned to the viewport edge
+//! in their direction. Drawn in the HUD's ortho space after the 3D bubble
+//! pass, so it's never hidden by terrain.
+
+use classicube_sys::{
+    Gfx_LoadMatrix, Gfx_SetTexturing, Matrix, MatrixType__MATRIX_VIEW, PackedCol_Make, Vec3,
+};
+
+use crate::plugin::{
+    events::chat_message::get_nick_name,
+    rendering::{
+        self,
+        bubble::helpers::{BubbleStyle, create_textures},
+        context::vertex_buffer::Texture_Render,
+    },
+};
+
+/// Inset from the viewport edge, in normalized device units.
+const EDGE_MARGIN: f32 = 0.08;
+
+/// CP437 arrows (0x1B / 0x1A / 0x18 / 0x19); `OwnedString::new` maps them back.
+const ARROW_LEFT: char = '\u{2190}';
+const ARROW_RIGHT: char = '\u{2192}';
+const ARROW_UP: char = '\u{2191}';
+const ARROW_DOWN: char = '\u{2193}';
+
+/// Row-vector `v * m`, as ClassiCube's matrices expect.
+fn transform(v: [f32; 4], m: &Matrix) -> [f32; 4] {
+    [
+        v[0] * m.row1.x + v[1] * m.row2.x + v[2] * m.row3.x + v[3] * m.row4.x,
+        v[0] * m.row1.y + v[1] * m.row2.y + v[2] * m.row3.y + v[3] * m.row4.y,
+        v[0] * m.row1.z + v[1] * m.row2.z + v[2] * m.row3.z + v[3] * m.row4.z,
+        v[0] * m.row1.w + v[1] * m.row2.w + v[2] * m.row3.w + v[3] * m.row4.w,
+    ]
+}
+
+/// `clip` is the anchor in clip space. Returns `None` when it's on screen,
+/// otherwise the point on the inset viewport border in the anchor's
+/// direction, in normalized device coordinates (y up).
+fn edge_point(clip: [f32; 4]) -> Option<(f32, f32)> {
+    let [x, y, _, w] = clip;
+    if w > 0.0 {
+        let (nx, ny) = (x / w, y / w);
+        if nx.abs() <= 1.0 && ny.abs() <= 1.0 {
+            return None;
+        }
+    }
+    // Behind the camera the perspective divide mirrors the point; use the
+    // undivided direction, flipped, instead.
+    let (dx, dy) = if w > 0.0 { (x, y) } else { (-x, -y) };
+    let largest = dx.abs().max(dy.abs());
+    if largest <= f32::EPSILON {
+        // Directly behind: call it "down".
+        return Some((0.0, -(1.0 - EDGE_MARGIN)));
+    }
+    let reach = (1.0 - EDGE_MARGIN) / largest;
+    Some((dx * reach, dy * reach))
+}
+
+fn label_text(nick: &str, (x, y): (f32, f32)) -> String {
+    if x.abs() >= y.abs() {
+        if x < 0.0 {
+            format!("&f{ARROW_LEFT} {nick}")
+        } else {
+            format!("{nick} &f{ARROW_RIGHT}")
+        }
+    } else if y > 0.0 {
+        format!("&f{ARROW_UP} {nick}")
+    } else {
+        format!("&f{ARROW_DOWN} {nick}")
+    }
+}
+
+/// Called with the 2D ortho projection and an identity view loaded;
+/// `view_projection` is the 3D camera's, for projecting the anchors.
+pub fn render(view_projection: &Matrix, width: f32, height: f32) {
+    for (id, anchor) in rendering::speaking_anchors() {
+        let Vec3 { x, y, z } = anchor;
+        let Some(point) = edge_point(transform([x, y, z, 1.0], view_projection)) else {
+            continue;
+        };
+        let nick = get_nick_name(id).unwrap_or_else(|| format!("#{id}"));
+        let text = label_text(&nick, point);
+
+        // Same nick and side as last frame is a cache hit.
+        let Some(textures) = create_textures(std::slice::from_ref(&text), BubbleStyle::Borderless)
+        else {
+            continue;
+        };
+        let mut texture = textures.front;
+        let (half_width, half_height) = (texture.width as f32 / 2.0, texture.height as f32 / 2.0);
+        // Keep long names fully on screen.
+        let screen_x =
+            ((point.0 * 0.5 + 0.5) * width).clamp(half_width, (width - half_width).max(half_width));
+        let screen_y = ((0.5 - point.1 * 0.5) * height)
+            .clamp(half_height, (height - half_height).max(half_height));
+        // Textures are anchored bottom-center; center them on the point.
+        let m = Matrix::translate(screen_x, screen_y + half_height, 0.0);
+        unsafe {
+            Gfx_LoadMatrix(MatrixType__MATRIX_VIEW, &m);
+            Gfx_SetTexturing(1);
+            Texture_Render(&mut texture, PackedCol_Make(255, 255, 255, 255), true);
+        }
+    }
+
+    unsafe {
+        Gfx_LoadMatrix(MatrixType__MATRIX_VIEW, &Matrix::IDENTITY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EDGE_MARGIN, edge_point, label_text};
+
+    #[test]
+    fn on_screen_anchors_get_no_indicator() {
+        assert_eq!(edge_point([0.5, -0.5, 0.0, 1.0]), None);
+        assert_eq!(edge_point([2.0, 2.0, 0.0, 4.0]), None);
+    }
+
+    #[test]
+    fn off_screen_anchors_clamp_to_the_edge() {
+        let edge = 1.0 - EDGE_MARGIN;
+        assert_eq!(edge_point([3.0, 0.0, 0.0, 1.0]), Some((edge, 0.0)));
+        let (x, y) = edge_point([-4.0, 2.0, 0.0, 1.0]).unwrap();
+        assert_eq!(x, -edge);
+        assert!((y - edge / 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn behind_the_camera_points_the_other_way() {
+        // Mirrored by the divide; the indicator should point left, not right.
+        let (x, _) = edge_point([1.0, 0.0, 0.0, -1.0]).unwrap();
+        assert!(x < 0.0);
+    }
+
+    #[test]
+    fn arrow_follows_the_dominant_axis() {
+        assert!(label_text("Bob", (-0.9, 0.2)).contains('\u{2190}'));
+        assert!(label_text("Bob", (0.9, 0.2)).ends_with('\u{2192}'));
+        assert!(label_text("Bob", (0.1, 0.9)).contains('\u{2191}'));
+        assert!(label_text("Bob", (0.1, -0.9)).contains('\u{2193}'));
+    }
+}
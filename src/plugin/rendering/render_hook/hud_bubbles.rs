@@ -0,0 +1,147 @@
+//! Chat relayed from IRC/Discord, drawn as a column of bordered bubbles in
+//! the top-right corner of the screen, oldest on top. The messages live as
+//! long as a world bubble with the same text would, then fade out.
+
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use classicube_sys::{
+    Gfx_LoadMatrix, Gfx_SetAlphaArgBlend, Gfx_SetTexturing, Matrix, MatrixType__MATRIX_VIEW,
+    PackedCol_Make,
+};
+
+use crate::plugin::{
+    events::bridged_chat::{self, BridgedMessage},
+    rendering::{
+        bubble::{
+            helpers::{BubbleStyle, create_textures},
+            message_lifetime,
+        },
+        context::{
+            atlas::{self, AtlasTextures},
+            vertex_buffer::Texture_Render,
+        },
+    },
+    settings,
+};
+
+/// Distance from the screen edges, in pixels.
+const MARGIN: f32 = 8.0;
+/// Space between bubbles, in pixels.
+const GAP: f32 = 2.0;
+
+/// A message on screen and its bake, kept from frame to frame.
+struct Entry {
+    received: Instant,
+    lines: Vec<String>,
+    epoch: u32,
+    textures: Rc<AtlasTextures>,
+}
+
+thread_local!(
+    static ENTRIES: RefCell<Vec<Entry>> = const { RefCell::new(Vec::new()) };
+);
+
+/// The bake `message` had last frame, or a new one if its lines changed or
+/// the context was lost since.
+fn entry_for(cached: &[Entry], message: BridgedMessage) -> Option<Entry> {
+    let epoch = atlas::epoch();
+    let textures = match cached.iter().find(|entry| {
+        entry.received == message.received && entry.lines == message.lines && entry.epoch == epoch
+    }) {
+        Some(entry) => entry.textures.clone(),
+        None => create_textures(&message.lines, BubbleStyle::Bordered)?,
+    };
+    Some(Entry {
+        received: message.received,
+        lines: message.lines,
+        epoch,
+        textures,
+    })
+}
+
+/// Opacity of a message `age` old that stays up for `lifetime` and then
+/// fades over `fade`. `None` once it's gone.
+fn alpha(age: Duration, lifetime: Duration, fade: Duration) -> Option<f32> {
+    let Some(past) = age.checked_sub(lifetime) else {
+        return Some(1.0);
+    };
+    if past >= fade {
+        None
+    } else {
+        Some(1.0 - past.as_secs_f32() / fade.as_secs_f32())
+    }
+}
+
+/// Called with the 2D ortho projection and an identity view loaded.
+pub fn render(width: f32) {
+    let settings = settings::get();
+    let now = Instant::now();
+    let cached = ENTRIES.take();
+    let mut entries = Vec::with_capacity(cached.len());
+    let mut y = MARGIN;
+    unsafe {
+        // D3D9 ignores vertex alpha without this, so the fade would pop.
+        Gfx_SetAlphaArgBlend(1);
+    }
+    for message in bridged_chat::messages() {
+        let age = now.saturating_duration_since(message.received);
+        let lifetime = message_lifetime(&message.lines, &settings);
+        let Some(alpha) = alpha(age, lifetime, settings.fly_away_duration) else {
+            continue;
+        };
+        let Some(entry) = entry_for(&cached, message) else {
+            continue;
+        };
+        let mut texture = entry.textures.front;
+        entries.push(entry);
+        let (half_width, height) = (texture.width as f32 / 2.0, texture.height as f32);
+        // Textures are anchored bottom-center.
+        let m = Matrix::translate(width - MARGIN - half_width, y + height, 0.0);
+        unsafe {
+            Gfx_LoadMatrix(MatrixType__MATRIX_VIEW, &m);
+            Gfx_SetTexturing(1);
+            Texture_Render(
+                &mut texture,
+                PackedCol_Make(255, 255, 255, (alpha * 255.0) as u8),
+                true,
+            );
+        }
+        y += height + GAP;
+    }
+
+    // What's left in `cached` belongs to messages gone since last frame and
+    // is released on return.
+    ENTRIES.set(entries);
+
+    unsafe {
+        Gfx_SetAlphaArgBlend(0);
+        Gfx_LoadMatrix(MatrixType__MATRIX_VIEW, &Matrix::IDENTITY);
+    }
+}
+
+pub fn free() {
+    ENTRIES.take();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::alpha;
+
+    #[test]
+    fn fades_out_after_its_lifetime() {
+        let secs = Duration::from_secs_f32;
+        assert_eq!(alpha(secs(1.0), secs(5.0), secs(0.4)), Some(1.0));
+        assert_eq!(
+            alpha(secs(5.2), secs(5.0), secs(0.4)).map(|a| (a * 10.0).round()),
+            Some(5.0)
+        );
+        assert_eq!(alpha(secs(5.4), secs(5.0), secs(0.4)), None);
+        assert_eq!(alpha(secs(5.0), secs(5.0), Duration::ZERO), None);
+    }
+}
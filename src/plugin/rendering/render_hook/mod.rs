@@ -1,3 +1,5 @@
+mod edge_indicators;
+mod hud_bubbles;
 pub mod renderable;
 
 use std::{cell::Cell, ffi::c_void};
@@ -8,6 +10,8 @@ use classicube_sys::{
     screen::Priority,
 };
 
+use crate::plugin::settings::{self, Occlusion};
+
 /// Mirror ClassiCube's per-backend `Gfx_CalcOrthoMatrix`, picking the formula
 /// at compile time. `Matrix::orthographic` is GL-flavored (clip-space z `[-1, 1]`)
 /// — feeding it to D3D9/D3D11 (clip-space z `[0, 1]`) puts every 2D vertex
@@ -45,19 +49,30 @@ fn calc_ortho_matrix(width: f32, height: f32, z_near: f32, z_far: f32) -> Matrix
 /// the HUD (and any later screens) see the state they expect.
 unsafe extern "C" fn render(_: *mut c_void, _: f32) {
     crate::plugin::events::local_presence::poll();
+    let settings = settings::get();
     unsafe {
-        Gfx_SetDepthTest(1);
+        // The world's depth buffer is still intact here, so depth-testing
+        // against it is what lets terrain hide bubbles.
+        Gfx_SetDepthTest(match settings.occlusion {
+            Occlusion::Always => 0,
+            Occlusion::Hidden | Occlusion::Dimmed => 1,
+        });
         // Depth-write OFF: bubbles are translucent quads. Leaving depth-write
         // on would have each bubble's depth occlude later-drawn bubbles at the
         // same depth, causing z-fighting when stacks momentarily overlap (e.g.
         // during the spawn-ease-up tween). With depth-write off, later bubbles
-        // always overdraw earlier ones — matching the iteration order
-        // (oldest → newest), so the newest message stays visually on top.
+        // always overdraw earlier ones. `batch::flush` orders speakers back to
+        // front and each speaker's stack oldest → newest, so nearer speakers
+        // and the newest message stay visually on top.
         Gfx_SetDepthWrite(0);
         Gfx_SetAlphaBlending(0);
         Gfx_LoadMatrix(MatrixType__MATRIX_PROJ, &raw const Gfx.Projection);
 
-        renderable::render_all();
+        // While disabled, bubbles keep tracking events but nothing is drawn,
+        // so turning them back on shows the current state right away.
+        if settings.enabled {
+            renderable::render_all();
+        }
 
         // Reconstruct the 2D ortho the engine's `Gfx_Begin2D` had loaded.
         // Must use a backend-correct formula: `Matrix::orthographic` is
@@ -75,6 +90,15 @@ unsafe extern "C" fn render(_: *mut c_void, _: f32) {
         // is to leave alpha-test off; otherwise translucent HUD gradients (chat
         // backdrop, escape menu backdrop) get their <128-alpha pixels discarded.
         Gfx_SetAlphaTest(0);
+
+        // Drawn over the HUD-space state we just restored; `Gfx.View` and
+        // `Gfx.Projection` still hold the 3D camera for projecting anchors.
+        if settings.enabled && settings.edge_indicators {
+            edge_indicators::render(&(Gfx.View * Gfx.Projection), width, height);
+        }
+        if settings.enabled && settings.bridge_bubbles {
+            hud_bubbles::render(width);
+        }
     }
 }
 
@@ -95,4 +119,5 @@ pub fn initialize() {
 pub fn free() {
     // Dropping the OwnedScreen calls Gui_Remove and frees the screen + vtable boxes.
     SCREEN.take();
+    hud_bubbles::free();
 }
@@ -3,7 +3,10 @@ use std::{
     rc::{Rc, Weak},
 };
 
+use crate::plugin::rendering::context::batch;
+
 pub trait Renderable {
+    /// Queue quads with `batch::push`; `render_all` draws them together.
     fn render(&mut self);
 }
 
@@ -54,7 +57,8 @@ pub fn render_all() {
                 false
             }
         })
-    })
+    });
+    batch::flush();
 }
 
 #[test]
@@ -0,0 +1,667 @@
+//! User-tunable values, persisted in ClassiCube's `options.txt` under
+//! `chatbubbles-*` keys. Everything that used to be a hardcoded constant and
+//! is a matter of taste lives here; modules read the current values each time
+//! they need them, so changes apply without a restart.
+
+use std::{
+    cell::{Cell, RefCell},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use tracing::{debug, warn};
+
+use crate::plugin::events::player_chat_event::local_handler::HEARTBEAT_INTERVAL;
+
+/// Shortest `status_timeout` allowed: two heartbeats, so a late one plus
+/// relay latency doesn't let a status lapse. Keep `status-timeout`'s help in
+/// step.
+const MIN_STATUS_TIMEOUT: Duration = HEARTBEAT_INTERVAL.saturating_mul(2);
+
+/// How a bubble is rotated in the world.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    /// Follows the speaker's head yaw and pitch, like a sign held above it.
+    /// Seen from behind you get the mirrored back face.
+    HeadLocked,
+    /// Always faces the camera, so it's readable from any angle.
+    Billboard,
+}
+
+impl Orientation {
+    fn name(self) -> &'static str {
+        match self {
+            Self::HeadLocked => "head",
+            Self::Billboard => "billboard",
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "head" => Ok(Self::HeadLocked),
+            "billboard" => Ok(Self::Billboard),
+            _ => bail!("expected head or billboard, got {value:?}"),
+        }
+    }
+}
+
+/// What happens to bubbles behind world geometry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Occlusion {
+    /// Drawn over everything.
+    Always,
+    /// Depth-tested against the world, so walls hide them.
+    Hidden,
+    /// Hidden parts are drawn again, faint, when a wall is in the way.
+    Dimmed,
+}
+
+impl Occlusion {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Always => "always",
+            Self::Hidden => "hidden",
+            Self::Dimmed => "dimmed",
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "always" => Ok(Self::Always),
+            "hidden" => Ok(Self::Hidden),
+            "dimmed" => Ok(Self::Dimmed),
+            _ => bail!("expected always, hidden or dimmed, got {value:?}"),
+        }
+    }
+}
+
+/// How someone typing is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypingIndicator {
+    /// A preview of what they're typing, when they share it.
+    Text,
+    /// Three bouncing dots, whatever they share.
+    Dots,
+}
+
+impl TypingIndicator {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Dots => "dots",
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "dots" => Ok(Self::Dots),
+            _ => bail!("expected text or dots, got {value:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    /// Draw bubbles at all. Events are still tracked while off.
+    pub enabled: bool,
+    /// Share what we type; when off others get a `...` placeholder.
+    pub typing_previews: bool,
+    /// How others typing is shown to us. The `...` placeholder is always
+    /// drawn as dots.
+    pub typing_indicator: TypingIndicator,
+    /// Show the borderless menu / tab list / AFK icons.
+    pub show_icons: bool,
+    /// Shortest time a message stays up; longer ones get more, see
+    /// `reading_speed`.
+    pub message_lifetime: Duration,
+    /// Characters per second a message is assumed to be read at; 0 gives
+    /// every message `message_lifetime`.
+    pub reading_speed: f32,
+    /// Longest time a message stays up, however long it is.
+    pub max_message_lifetime: Duration,
+    pub spawn_duration: Duration,
+    pub fly_away_duration: Duration,
+    /// How much an older bubble's tail overlaps the newer bubble's top edge,
+    /// in world units.
+    pub stack_overlap: f32,
+    pub font_size: u8,
+    /// How long a heartbeat-capable peer's status survives without a refresh.
+    pub status_timeout: Duration,
+    /// Idle time before we report ourselves AFK.
+    pub afk_timeout: Duration,
+    /// Bubbles farther than this from the camera aren't drawn, in blocks; 0
+    /// means no limit.
+    pub max_distance: f32,
+    /// Width of the band before `max_distance` where bubbles fade out.
+    pub fade_distance: f32,
+    /// Far bubbles are scaled up so a line of text stays at least this many
+    /// pixels tall; 0 disables.
+    pub min_size: u8,
+    /// For bordered bubbles: messages and typing previews.
+    pub bordered_orientation: Orientation,
+    /// For borderless presence icons.
+    pub borderless_orientation: Orientation,
+    /// Pin a name and arrow to the screen edge for speakers whose bubble is
+    /// off-screen.
+    pub edge_indicators: bool,
+    pub occlusion: Occlusion,
+    /// Show chat relayed from IRC/Discord as a column of bubbles on screen.
+    pub bridge_bubbles: bool,
+    /// Messages a player can have up at once before the oldest fly away
+    /// early; 0 means no limit.
+    pub max_stack: u8,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            typing_previews: true,
+            typing_indicator: TypingIndicator::Text,
+            show_icons: true,
+            message_lifetime: Duration::from_secs(5),
+            reading_speed: 15.0,
+            max_message_lifetime: Duration::from_secs(15),
+            spawn_duration: Duration::from_millis(200),
+            fly_away_duration: Duration::from_millis(400),
+            // Tuned to the original single-line look
+            // (`BUBBLE_HEIGHT 0.5 - 0.20 = 0.30` advance).
+            stack_overlap: 0.20,
+            font_size: 8,
+            // Three missed `local_handler::HEARTBEAT_INTERVAL`s, so one
+            // dropped packet doesn't blink the bubble.
+            status_timeout: Duration::from_secs(15),
+            afk_timeout: Duration::from_secs(120),
+            max_distance: 0.0,
+            fade_distance: 8.0,
+            min_size: 0,
+            bordered_orientation: Orientation::HeadLocked,
+            borderless_orientation: Orientation::HeadLocked,
+            edge_indicators: false,
+            occlusion: Occlusion::Hidden,
+            bridge_bubbles: true,
+            max_stack: 0,
+        }
+    }
+}
+
+/// One persisted setting: its option key (without prefix) and how to convert
+/// it to and from the string stored in `options.txt`.
+struct Field {
+    name: &'static str,
+    help: &'static str,
+    get: fn(&Settings) -> String,
+    set: fn(&mut Settings, &str) -> Result<()>,
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "on" | "yes" | "1" => Ok(true),
+        "false" | "off" | "no" | "0" => Ok(false),
+        _ => bail!("expected true or false, got {value:?}"),
+    }
+}
+
+fn parse_f32(value: &str, min: f32, max: f32) -> Result<f32> {
+    let parsed: f32 = value
+        .parse()
+        .with_context(|| format!("expected a number, got {value:?}"))?;
+    if !(min..=max).contains(&parsed) {
+        bail!("must be between {min} and {max}");
+    }
+    Ok(parsed)
+}
+
+fn parse_u8(value: &str, min: u8, max: u8) -> Result<u8> {
+    let parsed: u8 = value
+        .parse()
+        .with_context(|| format!("expected a whole number, got {value:?}"))?;
+    if !(min..=max).contains(&parsed) {
+        bail!("must be between {min} and {max}");
+    }
+    Ok(parsed)
+}
+
+fn parse_secs(value: &str, min: f32, max: f32) -> Result<Duration> {
+    parse_f32(value, min, max).map(Duration::from_secs_f32)
+}
+
+fn format_secs(duration: Duration) -> String {
+    duration.as_secs_f32().to_string()
+}
+
+const FIELDS: &[Field] = &[
+    Field {
+        name: "enabled",
+        help: "true/false",
+        get: |s| s.enabled.to_string(),
+        set: |s, v| {
+            s.enabled = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "typing-previews",
+        help: "true/false",
+        get: |s| s.typing_previews.to_string(),
+        set: |s, v| {
+            s.typing_previews = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "typing-indicator",
+        help: "text/dots",
+        get: |s| s.typing_indicator.name().to_string(),
+        set: |s, v| {
+            s.typing_indicator = TypingIndicator::parse(v)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "show-icons",
+        help: "true/false",
+        get: |s| s.show_icons.to_string(),
+        set: |s, v| {
+            s.show_icons = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "message-lifetime",
+        help: "seconds, 0.5-60",
+        get: |s| format_secs(s.message_lifetime),
+        set: |s, v| {
+            s.message_lifetime = parse_secs(v, 0.5, 60.0)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "reading-speed",
+        help: "characters per second, 0 (flat lifetime)-100",
+        get: |s| s.reading_speed.to_string(),
+        set: |s, v| {
+            s.reading_speed = parse_f32(v, 0.0, 100.0)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "max-message-lifetime",
+        help: "seconds, 0.5-120",
+        get: |s| format_secs(s.max_message_lifetime),
+        set: |s, v| {
+            s.max_message_lifetime = parse_secs(v, 0.5, 120.0)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "spawn-duration",
+        help: "seconds, 0-2",
+        get: |s| format_secs(s.spawn_duration),
+        set: |s, v| {
+            s.spawn_duration = parse_secs(v, 0.0, 2.0)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "fly-away-duration",
+        help: "seconds, 0-2",
+        get: |s| format_secs(s.fly_away_duration),
+        set: |s, v| {
+            s.fly_away_duration = parse_secs(v, 0.0, 2.0)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "stack-overlap",
+        help: "blocks, 0-0.4",
+        get: |s| s.stack_overlap.to_string(),
+        set: |s, v| {
+            s.stack_overlap = parse_f32(v, 0.0, 0.4)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "font-size",
+        help: "6-32",
+        get: |s| s.font_size.to_string(),
+        set: |s, v| {
+            s.font_size = parse_u8(v, 6, 32)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "status-timeout",
+        help: "seconds, 10-120",
+        get: |s| format_secs(s.status_timeout),
+        set: |s, v| {
+            s.status_timeout = parse_secs(v, MIN_STATUS_TIMEOUT.as_secs_f32(), 120.0)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "afk-timeout",
+        help: "seconds, 10-3600",
+        get: |s| format_secs(s.afk_timeout),
+        set: |s, v| {
+            s.afk_timeout = parse_secs(v, 10.0, 3600.0)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "max-distance",
+        help: "blocks, 0 (no limit)-512",
+        get: |s| s.max_distance.to_string(),
+        set: |s, v| {
+            s.max_distance = parse_f32(v, 0.0, 512.0)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "fade-distance",
+        help: "blocks, 0-64",
+        get: |s| s.fade_distance.to_string(),
+        set: |s, v| {
+            s.fade_distance = parse_f32(v, 0.0, 64.0)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "min-size",
+        help: "pixels, 0 (off)-64",
+        get: |s| s.min_size.to_string(),
+        set: |s, v| {
+            s.min_size = parse_u8(v, 0, 64)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "bordered-orientation",
+        help: "head/billboard",
+        get: |s| s.bordered_orientation.name().to_string(),
+        set: |s, v| {
+            s.bordered_orientation = Orientation::parse(v)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "borderless-orientation",
+        help: "head/billboard",
+        get: |s| s.borderless_orientation.name().to_string(),
+        set: |s, v| {
+            s.borderless_orientation = Orientation::parse(v)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "edge-indicators",
+        help: "true/false",
+        get: |s| s.edge_indicators.to_string(),
+        set: |s, v| {
+            s.edge_indicators = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "occlusion",
+        help: "always/hidden/dimmed",
+        get: |s| s.occlusion.name().to_string(),
+        set: |s, v| {
+            s.occlusion = Occlusion::parse(v)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "bridge-bubbles",
+        help: "true/false",
+        get: |s| s.bridge_bubbles.to_string(),
+        set: |s, v| {
+            s.bridge_bubbles = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Field {
+        name: "max-stack",
+        help: "messages, 0 (no limit)-50",
+        get: |s| s.max_stack.to_string(),
+        set: |s, v| {
+            s.max_stack = parse_u8(v, 0, 50)?;
+            Ok(())
+        },
+    },
+];
+
+impl Settings {
+    /// Fill in every field `read` has a valid value for, keeping defaults
+    /// for missing or malformed ones.
+    fn load(read: impl Fn(&str) -> Option<String>) -> Self {
+        let mut settings = Self::default();
+        for field in FIELDS {
+            let Some(value) = read(field.name) else {
+                continue;
+            };
+            if let Err(e) = (field.set)(&mut settings, &value) {
+                warn!(field.name, ?value, "ignoring saved setting: {:#}", e);
+            }
+        }
+        settings
+    }
+
+    /// Hand every field that differs from `old` to `write`.
+    fn save_changes(&self, old: &Self, mut write: impl FnMut(&str, String)) {
+        for field in FIELDS {
+            let value = (field.get)(self);
+            if value != (field.get)(old) {
+                write(field.name, value);
+            }
+        }
+    }
+}
+
+thread_local!(
+    static SETTINGS: RefCell<Settings> = RefCell::new(Settings::default());
+);
+
+// Bumped on every change, so code that bakes settings into cached state
+// (bubble textures, fonts) can notice and redo it.
+thread_local!(
+    static GENERATION: Cell<u32> = const { Cell::new(0) };
+);
+
+pub fn get() -> Settings {
+    SETTINGS.with_borrow(|settings| *settings)
+}
+
+pub fn generation() -> u32 {
+    GENERATION.get()
+}
+
+/// Apply `f` to the current settings and persist whatever changed.
+pub fn update(f: impl FnOnce(&mut Settings)) {
+    let (old, new) = SETTINGS.with_borrow_mut(|settings| {
+        let old = *settings;
+        f(settings);
+        (old, *settings)
+    });
+    if old == new {
+        return;
+    }
+    new.save_changes(&old, write_option);
+    GENERATION.set(GENERATION.get().wrapping_add(1));
+}
+
+/// `set <name> <value>` from the command line. Returns the stored value.
+pub fn set_by_name(name: &str, value: &str) -> Result<String> {
+    let field = FIELDS
+        .iter()
+        .find(|field| field.name.eq_ignore_ascii_case(name))
+        .with_context(|| format!("unknown setting {name:?}"))?;
+    let mut next = get();
+    (field.set)(&mut next, value).with_context(|| field.name)?;
+    update(|settings| *settings = next);
+    Ok((field.get)(&next))
+}
+
+/// `(name, current value, help)` for every setting.
+pub fn describe() -> Vec<(&'static str, String, &'static str)> {
+    let settings = get();
+    FIELDS
+        .iter()
+        .map(|field| (field.name, (field.get)(&settings), field.help))
+        .collect()
+}
+
+/// Longest option value we read back. Enough for list-valued options like
+/// the ignore list, well past any single setting.
+pub const OPTION_CAPACITY: usize = 1024;
+
+#[cfg(not(test))]
+mod options {
+    use std::{ffi::CString, os::raw::c_char};
+
+    use classicube_sys::{Options_Get, Options_Set, OwnedString, cc_string};
+    use tracing::debug;
+
+    use super::OPTION_CAPACITY;
+
+    const KEY_PREFIX: &str = "chatbubbles-";
+
+    fn option_key(name: &str) -> CString {
+        CString::new(format!("{KEY_PREFIX}{name}")).unwrap()
+    }
+
+    /// Raw `chatbubbles-<name>` value, or `None` if unset or empty.
+    pub fn read_option(name: &str) -> Option<String> {
+        let key = option_key(name);
+        let mut buffer = [0 as c_char; OPTION_CAPACITY];
+        let mut value = cc_string {
+            buffer: buffer.as_mut_ptr(),
+            length: 0,
+            capacity: OPTION_CAPACITY as _,
+        };
+        unsafe {
+            Options_Get(key.as_ptr(), &mut value, c"".as_ptr());
+        }
+        let value = value.to_string();
+        (!value.is_empty()).then_some(value)
+    }
+
+    pub fn write_option(name: &str, value: String) {
+        debug!(?name, ?value, "saving setting");
+        let key = option_key(name);
+        let value = OwnedString::new(value);
+        unsafe {
+            Options_Set(key.as_ptr(), &value.get_cc_string());
+        }
+    }
+}
+
+/// Unit tests run outside the game: there's no `options.txt` to read, and
+/// changing settings in a test mustn't write to one.
+#[cfg(test)]
+mod options {
+    pub fn read_option(_name: &str) -> Option<String> {
+        None
+    }
+
+    pub fn write_option(_name: &str, _value: String) {}
+}
+
+pub use self::options::{read_option, write_option};
+
+pub fn initialize() {
+    let settings = Settings::load(read_option);
+    debug!(?settings, "loaded settings");
+    SETTINGS.with_borrow_mut(|slot| *slot = settings);
+    GENERATION.set(GENERATION.get().wrapping_add(1));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use super::{FIELDS, MIN_STATUS_TIMEOUT, Settings};
+
+    #[test]
+    fn load_keeps_defaults_for_missing_and_bad_values() {
+        let stored = HashMap::from([
+            ("message-lifetime", "2.5"),
+            ("font-size", "12"),
+            ("show-icons", "false"),
+            ("stack-overlap", "9000"),
+            ("afk-timeout", "soon"),
+        ]);
+        let settings = Settings::load(|key| stored.get(key).map(ToString::to_string));
+
+        assert_eq!(settings.message_lifetime, Duration::from_millis(2500));
+        assert_eq!(settings.font_size, 12);
+        assert!(!settings.show_icons);
+        assert_eq!(settings.stack_overlap, Settings::default().stack_overlap);
+        assert_eq!(settings.afk_timeout, Settings::default().afk_timeout);
+    }
+
+    #[test]
+    fn whole_number_fields_reject_fractions() {
+        let stored = HashMap::from([
+            ("font-size", "12.7"),
+            ("min-size", "16"),
+            ("max-stack", "-1"),
+        ]);
+        let settings = Settings::load(|key| stored.get(key).map(ToString::to_string));
+
+        assert_eq!(settings.font_size, Settings::default().font_size);
+        assert_eq!(settings.min_size, 16);
+        assert_eq!(settings.max_stack, Settings::default().max_stack);
+    }
+
+    #[test]
+    fn status_timeout_outlasts_two_heartbeats() {
+        let field = FIELDS
+            .iter()
+            .find(|field| field.name == "status-timeout")
+            .unwrap();
+        let min = MIN_STATUS_TIMEOUT.as_secs();
+        assert!(field.help.starts_with(&format!("seconds, {min}-")));
+
+        let mut settings = Settings::default();
+        assert!((field.set)(&mut settings, "6").is_err());
+        assert!((field.set)(&mut settings, &min.to_string()).is_ok());
+        assert_eq!(settings.status_timeout, MIN_STATUS_TIMEOUT);
+    }
+
+    #[test]
+    fn save_writes_only_changed_fields() {
+        let old = Settings::default();
+        let new = Settings {
+            font_size: 10,
+            enabled: false,
+            ..old
+        };
+        let mut written = Vec::new();
+        new.save_changes(&old, |key, value| written.push((key.to_string(), value)));
+        assert_eq!(
+            written,
+            vec![
+                ("enabled".to_string(), "false".to_string()),
+                ("font-size".to_string(), "10".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn saved_values_load_back() {
+        let old = Settings::default();
+        let new = Settings {
+            message_lifetime: Duration::from_millis(7500),
+            status_timeout: Duration::from_secs(30),
+            typing_previews: false,
+            ..old
+        };
+        let mut stored = HashMap::new();
+        new.save_changes(&old, |key, value| {
+            stored.insert(key.to_string(), value);
+        });
+        assert_eq!(Settings::load(|key| stored.get(key).cloned()), new);
+    }
+}